@@ -8,6 +8,7 @@ pub enum CommandError {
     TLSError(String),
     ConfigurationError(String),
     RegistryError(RegistryError),
+    PolicyError(String),
 }
 
 impl fmt::Display for CommandError {
@@ -17,6 +18,7 @@ impl fmt::Display for CommandError {
             CommandError::TLSError(err) => write!(f, "TLS error: {}", err),
             CommandError::ConfigurationError(err) => write!(f, "Configuration error: {}", err),
             CommandError::RegistryError(err) => write!(f, "Registry error: {}", err),
+            CommandError::PolicyError(err) => write!(f, "Policy error: {}", err),
         }
     }
 }
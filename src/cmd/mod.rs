@@ -1,7 +1,9 @@
 mod error;
+mod policy;
 mod scrub;
 mod server;
 
 pub use self::error::CommandError;
+pub use self::policy::{PolicyEval, PolicyEvalOptions};
 pub use self::scrub::{Scrub, ScrubOptions};
 pub use self::server::Server;
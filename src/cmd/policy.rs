@@ -0,0 +1,373 @@
+use crate::cmd::error::CommandError;
+use crate::configuration::Configuration;
+use crate::oci::{Digest, Reference};
+use crate::policy::{AccessPolicies, ClientAction, ClientIdentity, PolicyEvaluation, PolicyResult};
+use crate::registry::validate_namespace;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+
+pub struct PolicyEvalOptions {
+    pub tests: Option<String>,
+    pub request: PolicyRequest,
+}
+
+impl PolicyEvalOptions {
+    pub fn from_matches(matches: &clap::ArgMatches) -> Result<Self, CommandError> {
+        let get_many = |name: &str| -> Vec<String> {
+            matches
+                .get_many::<String>(name)
+                .map(|values| values.cloned().collect())
+                .unwrap_or_default()
+        };
+
+        let client_ip = matches
+            .get_one::<String>("client-ip")
+            .map(|ip| {
+                ip.parse().map_err(|_| {
+                    CommandError::PolicyError(format!("Invalid client IP address: {}", ip))
+                })
+            })
+            .transpose()?;
+
+        let mut headers = HashMap::new();
+        for header in get_many("header") {
+            let Some((name, value)) = header.split_once(':') else {
+                return Err(CommandError::PolicyError(format!(
+                    "Invalid header '{}', expected 'name: value'",
+                    header
+                )));
+            };
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+
+        Ok(Self {
+            tests: matches.get_one::<String>("tests").cloned(),
+            request: PolicyRequest {
+                identity: PolicyRequestIdentity {
+                    id: matches.get_one::<String>("identity-id").cloned(),
+                    username: matches.get_one::<String>("username").cloned(),
+                    cert_common_names: get_many("cert-cn"),
+                    cert_organizations: get_many("cert-o"),
+                    client_ip,
+                    headers,
+                },
+                action: get_many("action").join(" "),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PolicyRequestIdentity {
+    pub id: Option<String>,
+    pub username: Option<String>,
+    #[serde(default)]
+    pub cert_common_names: Vec<String>,
+    #[serde(default)]
+    pub cert_organizations: Vec<String>,
+    pub client_ip: Option<IpAddr>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+// A simulated request: an identity and an action, e.g. `put-manifest team/app:1.0`
+#[derive(Debug, Deserialize)]
+pub struct PolicyRequest {
+    #[serde(default)]
+    pub identity: PolicyRequestIdentity,
+    pub action: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyExpectation {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PolicyTestCase {
+    pub name: String,
+    #[serde(flatten)]
+    pub request: PolicyRequest,
+    pub expect: PolicyExpectation,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PolicyTestSuite {
+    pub cases: Vec<PolicyTestCase>,
+}
+
+struct PolicyExplanation {
+    allowed: bool,
+    reason: Option<String>,
+    evaluations: Vec<PolicyEvaluation>,
+}
+
+pub struct PolicyEval {
+    access_policies: AccessPolicies,
+    global_policies: Vec<String>,
+    repository_policies: HashMap<String, Vec<String>>,
+}
+
+impl PolicyEval {
+    pub fn try_from_config(config: &Configuration) -> Result<Self, CommandError> {
+        let access_policies = AccessPolicies::try_from_config(config)?;
+
+        let global_policies = config
+            .global
+            .as_ref()
+            .map(|global| global.policies.clone())
+            .unwrap_or_default();
+        let repository_policies = config
+            .repository
+            .iter()
+            .map(|repo| (repo.namespace.clone(), repo.policies.clone()))
+            .collect();
+
+        Ok(Self {
+            access_policies,
+            global_policies,
+            repository_policies,
+        })
+    }
+
+    pub fn run(&self, options: &PolicyEvalOptions) -> Result<(), CommandError> {
+        match &options.tests {
+            Some(path) => self.run_tests(path),
+            None => {
+                let explanation = self.explain(&options.request)?;
+                self.print_explanation(&options.request, &explanation);
+                Ok(())
+            }
+        }
+    }
+
+    fn run_tests(&self, path: &str) -> Result<(), CommandError> {
+        let suite = fs::read_to_string(path)?;
+        let suite: PolicyTestSuite = serde_yaml::from_str(&suite).map_err(|e| {
+            CommandError::PolicyError(format!("Unable to parse test cases '{}': {}", path, e))
+        })?;
+
+        let mut failed = 0;
+        for case in &suite.cases {
+            let explanation = self.explain(&case.request)?;
+            let expected = case.expect == PolicyExpectation::Allow;
+
+            if explanation.allowed == expected {
+                println!("PASS {}", case.name);
+            } else {
+                failed += 1;
+                println!(
+                    "FAIL {}: expected {}, got {}",
+                    case.name,
+                    decision_name(expected),
+                    decision_name(explanation.allowed)
+                );
+                self.print_explanation(&case.request, &explanation);
+            }
+        }
+
+        println!("{} passed, {} failed", suite.cases.len() - failed, failed);
+
+        if failed > 0 {
+            return Err(CommandError::PolicyError(format!(
+                "{} of {} test cases failed",
+                failed,
+                suite.cases.len()
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn explain(&self, request: &PolicyRequest) -> Result<PolicyExplanation, CommandError> {
+        let action = self.parse_action(&request.action)?;
+
+        let mut identity = ClientIdentity::new();
+        identity.cert_common_name = request.identity.cert_common_names.clone();
+        identity.cert_organizations = request.identity.cert_organizations.clone();
+        identity.headers = request.identity.headers.clone();
+        identity.client_ip = request.identity.client_ip;
+        if let Some(username) = &request.identity.username {
+            // Credentials are never verified when explaining an access
+            identity.set_credentials(username.clone(), String::new());
+        }
+
+        let identity_id = request.identity.id.clone().or_else(|| {
+            request
+                .identity
+                .username
+                .as_ref()
+                .and_then(|username| self.access_policies.get_identity_id(username))
+        });
+
+        let (result, evaluations) =
+            identity.explain_access(&self.access_policies, action, identity_id);
+
+        Ok(PolicyExplanation {
+            allowed: result.is_ok(),
+            reason: result.err().map(|e| e.to_string()),
+            evaluations,
+        })
+    }
+
+    fn parse_action(&self, action: &str) -> Result<ClientAction, CommandError> {
+        let invalid = || CommandError::PolicyError(format!("Invalid action '{}'", action));
+
+        let mut parts = action.split_whitespace();
+        let name = parts.next().ok_or_else(invalid)?;
+        let target = parts.next();
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        let action = match (name, target) {
+            ("get-api-version", None) => ClientAction::GetApiVersion,
+            ("list-catalog", None) => ClientAction::ListCatalog,
+            ("put-blob", Some(target)) => ClientAction::PutBlob(self.parse_namespace(target)?),
+            ("list-tags", Some(target)) => ClientAction::ListTags(self.parse_namespace(target)?),
+            ("get-blob" | "delete-blob" | "get-referrers", Some(target)) => {
+                let (namespace, digest) = target.rsplit_once('@').ok_or_else(invalid)?;
+                let namespace = self.parse_namespace(namespace)?;
+                let digest = Digest::try_from(digest).map_err(|_| invalid())?;
+
+                match name {
+                    "get-blob" => ClientAction::GetBlob(namespace, digest),
+                    "delete-blob" => ClientAction::DeleteBlob(namespace, digest),
+                    _ => ClientAction::GetReferrers(namespace, digest),
+                }
+            }
+            ("put-manifest" | "get-manifest" | "delete-manifest", Some(target)) => {
+                // `namespace@digest` or `namespace:tag`, namespaces never contain `:`
+                let (namespace, reference) = target
+                    .rsplit_once('@')
+                    .or_else(|| target.rsplit_once(':'))
+                    .ok_or_else(invalid)?;
+                let namespace = self.parse_namespace(namespace)?;
+                let reference = Reference::from_str(reference).map_err(|_| invalid())?;
+
+                match name {
+                    "put-manifest" => ClientAction::PutManifest(namespace, reference),
+                    "get-manifest" => ClientAction::GetManifest(namespace, reference),
+                    _ => ClientAction::DeleteManifest(namespace, reference),
+                }
+            }
+            _ => return Err(invalid()),
+        };
+
+        Ok(action)
+    }
+
+    fn parse_namespace(&self, namespace: &str) -> Result<String, CommandError> {
+        validate_namespace(namespace)
+            .map_err(|_| CommandError::PolicyError(format!("Invalid namespace '{}'", namespace)))?;
+
+        Ok(namespace.to_string())
+    }
+
+    fn describe_scope(&self, repository: &Option<String>) -> String {
+        match repository {
+            Some(repository) => format!(
+                "repository '{}' (default: {})",
+                repository,
+                decision_name(
+                    self.access_policies
+                        .is_repository_policy_default_allow(repository)
+                )
+            ),
+            None => format!(
+                "global (default: {})",
+                decision_name(self.access_policies.is_global_policy_default_allow())
+            ),
+        }
+    }
+
+    fn describe_policy(&self, repository: &Option<String>, index: usize) -> &str {
+        let policies = match repository {
+            Some(repository) => self.repository_policies.get(repository),
+            None => Some(&self.global_policies),
+        };
+
+        policies
+            .and_then(|policies| policies.get(index))
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    fn print_explanation(&self, request: &PolicyRequest, explanation: &PolicyExplanation) {
+        let identity = &request.identity;
+        println!("Action: {}", request.action);
+        println!(
+            "Identity: id={} username={} cert_common_names={:?} cert_organizations={:?}",
+            identity.id.as_deref().unwrap_or("-"),
+            identity.username.as_deref().unwrap_or("-"),
+            identity.cert_common_names,
+            identity.cert_organizations
+        );
+
+        let mut scope = None;
+        for evaluation in &explanation.evaluations {
+            if scope != Some(&evaluation.repository) {
+                scope = Some(&evaluation.repository);
+                println!("  {}", self.describe_scope(&evaluation.repository));
+            }
+
+            let decision = match evaluation.decision {
+                Some(allowed) => format!(" => {}", decision_name(allowed)),
+                None => String::new(),
+            };
+
+            match &evaluation.policy {
+                Some((index, result)) => {
+                    let result = match result {
+                        PolicyResult::Bool(value) => value.to_string(),
+                        PolicyResult::Invalid(value) => format!("invalid value {}", value),
+                        PolicyResult::Error(e) => format!("error: {}", e),
+                    };
+                    println!(
+                        "    #{} {} -> {}{}",
+                        index,
+                        self.describe_policy(&evaluation.repository, *index),
+                        result,
+                        decision
+                    );
+                }
+                None => println!("    default policy{}", decision),
+            }
+        }
+
+        // Each scope stops on its deciding evaluation, so the last one decided the access,
+        // unless the access was denied afterwards, e.g. when no repository matches
+        let decider = explanation
+            .evaluations
+            .last()
+            .filter(|evaluation| evaluation.decision == Some(explanation.allowed));
+        if let Some(evaluation) = decider {
+            let policy = match &evaluation.policy {
+                Some((index, _)) => format!("policy #{}", index),
+                None => "default policy".to_string(),
+            };
+            println!(
+                "Decided by: {} of {}",
+                policy,
+                self.describe_scope(&evaluation.repository)
+            );
+        }
+
+        match &explanation.reason {
+            Some(reason) => println!("Result: DENY ({})", reason),
+            None => println!("Result: ALLOW"),
+        }
+    }
+}
+
+fn decision_name(allowed: bool) -> &'static str {
+    if allowed {
+        "allow"
+    } else {
+        "deny"
+    }
+}
@@ -237,6 +237,8 @@ impl Scrub {
                     .await?;
                 self.check_subject(namespace, &revision, manifest.subject)
                     .await?;
+                self.check_manifests(namespace, &revision, &manifest.manifests)
+                    .await?;
             }
 
             if next_marker.is_none() {
@@ -312,6 +314,26 @@ impl Scrub {
         Ok(())
     }
 
+    async fn check_manifests(
+        &self,
+        namespace: &str,
+        revision: &Digest,
+        manifests: &Vec<Digest>,
+    ) -> Result<(), CommandError> {
+        for manifest_digest in manifests {
+            debug!(
+                "Checking {}@{} manifest link: {}",
+                namespace, revision, manifest_digest
+            );
+
+            let link_reference = LinkReference::Manifest(revision.clone(), manifest_digest.clone());
+            self.ensure_link(namespace, &link_reference, manifest_digest)
+                .await?;
+        }
+
+        Ok(())
+    }
+
     async fn ensure_link(
         &self,
         namespace: &str,
@@ -3,17 +3,20 @@ use crate::cmd::server::{
     UploadParameters, RANGE_RE,
 };
 use crate::error::RegistryError;
+use crate::metrics::{METRICS, OPENMETRICS_CONTENT_TYPE};
 use crate::oci::{Digest, ReferrerList};
 use crate::policy::{ClientAction, ClientIdentity};
-use crate::registry::{BlobData, NewUpload, Registry, RegistryResponseBody};
+use crate::registry::{BlobData, ByteRange, NewUpload, Registry, RegistryResponseBody};
 use http_body_util::BodyExt;
-use hyper::body::Incoming;
-use hyper::header::HeaderValue;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE};
 use hyper::{Request, Response, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::io::AsyncReadExt;
-use tracing::{instrument, warn};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
 
 pub fn parse_range_header(range_header: &HeaderValue) -> Result<(u64, u64), RegistryError> {
     let range_str = range_header.to_str().map_err(|e| {
@@ -51,6 +54,79 @@ pub fn parse_range_header(range_header: &HeaderValue) -> Result<(u64, u64), Regi
     Ok((start, end))
 }
 
+// Collects the media types accepted by the client, from all "Accept" headers.
+// Quality parameters are ignored, except for explicitly refused media types (q=0).
+pub fn parse_accept_header(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("Accept")
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .filter_map(|media_range| {
+            let mut parts = media_range.split(';').map(str::trim);
+            let media_type = parts.next().filter(|media_type| !media_type.is_empty())?;
+
+            let is_refused = parts.any(|parameter| {
+                parameter
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    .is_some_and(|quality| quality == 0.0)
+            });
+
+            (!is_refused).then(|| media_type.to_string())
+        })
+        .collect()
+}
+
+pub fn parse_content_length(headers: &HeaderMap) -> Result<Option<u64>, RegistryError> {
+    let Some(content_length) = headers.get(CONTENT_LENGTH) else {
+        return Ok(None);
+    };
+
+    let content_length = content_length
+        .to_str()
+        .ok()
+        .and_then(|content_length| content_length.parse::<u64>().ok())
+        .ok_or_else(|| {
+            warn!("Invalid Content-Length header: {:?}", content_length);
+            RegistryError::SizeInvalid
+        })?;
+
+    Ok(Some(content_length))
+}
+
+// Parses a "Range" request header (RFC 7233) into byte ranges.
+// Unparseable headers are ignored, as allowed by the RFC, by returning `None`.
+pub fn parse_byte_ranges(range_header: &HeaderValue) -> Option<Vec<ByteRange>> {
+    let range_str = range_header.to_str().ok()?;
+    let Some(ranges) = range_str.trim().strip_prefix("bytes=") else {
+        warn!("Unsupported Range header unit: {}", range_str);
+        return None;
+    };
+
+    let mut byte_ranges = Vec::new();
+    for range in ranges.split(',').map(str::trim) {
+        let (start, end) = range.split_once('-')?;
+
+        let byte_range = match (start.trim(), end.trim()) {
+            ("", length) => ByteRange::Suffix(length.parse().ok()?),
+            (start, "") => ByteRange::From(start.parse().ok()?),
+            (start, end) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                if start > end {
+                    warn!("Invalid Range header: {}", range_str);
+                    return None;
+                }
+                ByteRange::FromTo(start, end)
+            }
+        };
+
+        byte_ranges.push(byte_range);
+    }
+
+    Some(byte_ranges)
+}
+
 pub fn parse_query_parameters<T: DeserializeOwned + Default>(
     query: Option<&str>,
 ) -> Result<T, RegistryError> {
@@ -99,8 +175,125 @@ pub async fn handle_get_api_version(
 }
 
 #[instrument]
+pub async fn handle_get_healthz() -> Result<Response<RegistryResponseBody>, RegistryError> {
+    let res = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(RegistryResponseBody::fixed(
+            json!({ "status": "ok" }).to_string().into_bytes(),
+        ))?;
+
+    Ok(res)
+}
+
+#[instrument]
+pub async fn handle_get_readyz(
+    registry: &Registry,
+) -> Result<Response<RegistryResponseBody>, RegistryError> {
+    let mut ready = true;
+    let mut checks = serde_json::Map::new();
+
+    for (dependency, res) in registry.check_readiness().await {
+        let status = match res {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                warn!("Readiness check failed for {}: {}", dependency, e);
+                ready = false;
+                e.to_string()
+            }
+        };
+        checks.insert(dependency.to_string(), status.into());
+    }
+
+    let (status, body) = if ready {
+        (StatusCode::OK, json!({ "status": "ok", "checks": checks }))
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "status": "error", "checks": checks }),
+        )
+    };
+
+    let res = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(RegistryResponseBody::fixed(body.to_string().into_bytes()))?;
+
+    Ok(res)
+}
+
+#[instrument]
+pub async fn handle_get_metrics() -> Result<Response<RegistryResponseBody>, RegistryError> {
+    let metrics = METRICS.encode().map_err(|e| {
+        warn!("Unable to encode metrics: {}", e);
+        RegistryError::InternalServerError(Some("Unable to encode metrics".to_string()))
+    })?;
+
+    let res = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", OPENMETRICS_CONTENT_TYPE)
+        .body(RegistryResponseBody::fixed(metrics.into_bytes()))?;
+
+    Ok(res)
+}
+
+#[instrument(skip(request))]
+pub async fn handle_get_token(
+    registry: &Registry,
+    request: Request<Incoming>,
+    identity: ClientIdentity,
+) -> Result<Response<RegistryResponseBody>, RegistryError> {
+    let Some(token_issuer) = &registry.token_issuer else {
+        return Err(RegistryError::NotFound);
+    };
+
+    // Anonymous requests are granted a token too: repository policies are still
+    // evaluated when the token is used.
+    registry.validate_credentials(&identity.credentials)?;
+
+    #[derive(Serialize, Debug)]
+    struct TokenResponse {
+        token: String,
+        access_token: String,
+        expires_in: u64,
+        issued_at: String,
+    }
+
+    // The "scope" parameter may be repeated, so it can't be deserialized into a struct
+    let query: Vec<(String, String)> = parse_query_parameters(request.uri().query())?;
+
+    let mut service = None;
+    let mut scopes = Vec::new();
+    for (key, value) in query {
+        match key.as_str() {
+            "service" => service = Some(value),
+            "scope" => scopes.extend(value.split_whitespace().map(String::from)),
+            _ => {}
+        }
+    }
+
+    let issued_token = token_issuer.issue(identity.username(), service, scopes)?;
+
+    let token = TokenResponse {
+        token: issued_token.token.clone(),
+        access_token: issued_token.token,
+        expires_in: issued_token.expires_in,
+        issued_at: issued_token.issued_at,
+    };
+    let token = serde_json::to_string(&token)?;
+
+    let res = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(RegistryResponseBody::fixed(token.into_bytes()))?;
+
+    Ok(res)
+}
+
+#[instrument(skip(request))]
 pub async fn handle_get_manifest(
     registry: &Registry,
+    request: Request<Incoming>,
     identity: ClientIdentity,
     parameters: ManifestParameters,
 ) -> Result<Response<RegistryResponseBody>, RegistryError> {
@@ -109,8 +302,15 @@ pub async fn handle_get_manifest(
         ClientAction::GetManifest(parameters.name.clone(), parameters.reference.clone()),
     )?;
 
+    let accepted_media_types = parse_accept_header(request.headers());
+
     let manifest = registry
-        .get_manifest(&parameters.name, parameters.reference)
+        .get_manifest(
+            &parameters.name,
+            parameters.reference,
+            &accepted_media_types,
+            &identity,
+        )
         .await?;
 
     let res = if let Some(content_type) = manifest.media_type {
@@ -129,9 +329,10 @@ pub async fn handle_get_manifest(
     Ok(res)
 }
 
-#[instrument]
+#[instrument(skip(request))]
 pub async fn handle_head_manifest(
     registry: &Registry,
+    request: Request<Incoming>,
     identity: ClientIdentity,
     parameters: ManifestParameters,
 ) -> Result<Response<RegistryResponseBody>, RegistryError> {
@@ -140,8 +341,14 @@ pub async fn handle_head_manifest(
         ClientAction::GetManifest(parameters.name.clone(), parameters.reference.clone()),
     )?;
 
+    let accepted_media_types = parse_accept_header(request.headers());
+
     let manifest = registry
-        .head_manifest(&parameters.name, parameters.reference)
+        .head_manifest(
+            &parameters.name,
+            parameters.reference,
+            &accepted_media_types,
+        )
         .await?;
 
     let res = if let Some(media_type) = manifest.media_type {
@@ -174,16 +381,34 @@ pub async fn handle_get_blob(
         ClientAction::GetBlob(parameters.name.clone(), parameters.digest.clone()),
     )?;
 
-    let range = request
+    let ranges = request
         .headers()
         .get("range")
-        .map(parse_range_header)
-        .transpose()?;
+        .and_then(parse_byte_ranges)
+        .unwrap_or_default();
 
-    let res = match registry
-        .get_blob(&parameters.name, &parameters.digest, range)
-        .await?
+    let blob = match registry
+        .get_blob(&parameters.name, &parameters.digest, ranges)
+        .await
     {
+        Ok(blob) => blob,
+        Err(RegistryError::RangeNotSatisfiable) => {
+            let blob = registry
+                .head_blob(&parameters.name, parameters.digest)
+                .await?;
+
+            let mut res = RegistryError::RangeNotSatisfiable
+                .to_response_with_span_id(tracing::Span::current().id());
+            let content_range = format!("bytes */{}", blob.size);
+            if let Ok(content_range) = HeaderValue::from_str(&content_range) {
+                res.headers_mut().insert(CONTENT_RANGE, content_range);
+            }
+            return Ok(res);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let res = match blob {
         BlobData::RangedReader(reader, (start, end), total_length) => {
             let length = end - start + 1;
             let stream = reader.take(length);
@@ -197,6 +422,40 @@ pub async fn handle_get_blob(
                 .header("Content-Range", range)
                 .body(RegistryResponseBody::streaming(stream))?
         }
+        BlobData::MultipartRangedReader(readers, total_length) => {
+            let boundary = Uuid::new_v4().simple().to_string();
+
+            let mut content_length = 0;
+            let mut parts = Vec::with_capacity(readers.len());
+            for (i, (reader, (start, end))) in readers.into_iter().enumerate() {
+                let separator = if i == 0 { "" } else { "\r\n" };
+                let header = format!(
+                    "{}--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    separator, boundary, start, end, total_length
+                );
+
+                let length = end - start + 1;
+                content_length += header.len() as u64 + length;
+                parts.push((Bytes::from(header), reader.take(length)));
+            }
+
+            let trailer = format!("\r\n--{}--\r\n", boundary);
+            content_length += trailer.len() as u64;
+
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("Docker-Content-Digest", parameters.digest.to_string())
+                .header("Accept-Ranges", "bytes")
+                .header("Content-Length", content_length.to_string())
+                .header(
+                    "Content-Type",
+                    format!("multipart/byteranges; boundary={}", boundary),
+                )
+                .body(RegistryResponseBody::streaming_parts(
+                    parts,
+                    Bytes::from(trailer),
+                ))?
+        }
         BlobData::Reader(stream, total_length) => Response::builder()
             .status(StatusCode::OK)
             .header("Docker-Content-Digest", parameters.digest.to_string())
@@ -244,11 +503,14 @@ pub async fn handle_start_upload(
     identity: ClientIdentity,
     parameters: NewUploadParameters,
 ) -> Result<Response<RegistryResponseBody>, RegistryError> {
-    identity.can_do(registry, ClientAction::PutBlob(parameters.name.clone()))?;
+    let action = ClientAction::PutBlob(parameters.name.clone());
+    identity.can_do(registry, action.clone())?;
 
     #[derive(Deserialize, Default)]
     struct UploadQuery {
         digest: Option<String>,
+        mount: Option<String>,
+        from: Option<String>,
     }
 
     let query: UploadQuery = parse_query_parameters(request.uri().query())?;
@@ -257,7 +519,64 @@ pub async fn handle_start_upload(
         .map(|s| Digest::try_from(s.as_str()))
         .transpose()?;
 
-    let res = match registry.start_upload(&parameters.name, digest).await? {
+    // Cross-repository mount: if the blob can't be mounted (missing source link or insufficient
+    // permissions), fall back to a regular upload session as mandated by the spec.
+    let mut new_upload = None;
+    if let (Some(mount), Some(from)) = (query.mount, query.from) {
+        let mount = Digest::try_from(mount.as_str())?;
+
+        // Not being able to read the source is not a denial, the blob is uploaded instead
+        let can_read_source =
+            identity.is_allowed(registry, ClientAction::GetBlob(from.clone(), mount.clone()));
+
+        let mounted = if can_read_source {
+            let res = registry
+                .mount_blob(&parameters.name, &from, &mount, &identity)
+                .await;
+            // A blob missing from the source is not mounted, the client uploads it instead
+            if !matches!(res, Ok(false)) {
+                registry.audit(&identity, &action, Some(&mount), &res);
+            }
+            res?
+        } else {
+            false
+        };
+
+        if mounted {
+            new_upload = Some(NewUpload::ExistingBlob(mount));
+        } else {
+            info!(
+                "Unable to mount blob {} from {}, starting an upload session",
+                mount, from
+            );
+        }
+    }
+
+    // Monolithic upload: the whole blob is provided in the request body, along with its digest.
+    // Clients may send an empty body to only check whether the blob exists.
+    let content_length = parse_content_length(request.headers())?.filter(|&length| length > 0);
+
+    let new_upload = match (new_upload, digest, content_length) {
+        (Some(new_upload), _, _) => new_upload,
+        (None, Some(digest), Some(content_length)) => {
+            let body = request.into_data_stream();
+            let res = registry
+                .upload_blob(
+                    &parameters.name,
+                    digest.clone(),
+                    content_length,
+                    body,
+                    &identity,
+                )
+                .await;
+            registry.audit(&identity, &action, Some(&digest), &res);
+            res?;
+            NewUpload::ExistingBlob(digest)
+        }
+        (None, digest, _) => registry.start_upload(&parameters.name, digest).await?,
+    };
+
+    let res = match new_upload {
         NewUpload::ExistingBlob(digest) => Response::builder()
             .status(StatusCode::CREATED)
             .header(
@@ -266,12 +585,19 @@ pub async fn handle_start_upload(
             )
             .header("Docker-Content-Digest", digest.to_string())
             .body(RegistryResponseBody::empty())?,
-        NewUpload::Session(location, session_uuid) => Response::builder()
-            .status(StatusCode::ACCEPTED)
-            .header("Location", location)
-            .header("Range", "0-0")
-            .header("Docker-Upload-UUID", session_uuid.to_string())
-            .body(RegistryResponseBody::empty())?,
+        NewUpload::Session(location, session_uuid) => {
+            let mut res = Response::builder()
+                .status(StatusCode::ACCEPTED)
+                .header("Location", location)
+                .header("Range", "0-0")
+                .header("Docker-Upload-UUID", session_uuid.to_string());
+
+            if let Some(chunk_min_length) = registry.upload_chunk_min_length() {
+                res = res.header("OCI-Chunk-Min-Length", chunk_min_length.to_string());
+            }
+
+            res.body(RegistryResponseBody::empty())?
+        }
     };
 
     Ok(res)
@@ -292,25 +618,34 @@ pub async fn handle_patch_upload(
         .map(parse_range_header)
         .transpose()?;
 
-    let start_offset = range.map(|(start, _)| start);
+    let content_length = parse_content_length(request.headers())?;
 
     let body = request.into_data_stream();
     let location = format!("/v2/{}/blobs/uploads/{}", &parameters.name, parameters.uuid);
 
     let range_max = registry
-        .patch_upload(&parameters.name, parameters.uuid, start_offset, body)
+        .patch_upload(
+            &parameters.name,
+            parameters.uuid,
+            range,
+            content_length,
+            body,
+        )
         .await?;
     let range_max = format!("0-{}", range_max);
 
-    let res = Response::builder()
+    let mut res = Response::builder()
         .status(StatusCode::ACCEPTED)
         .header("Location", location)
         .header("Range", range_max)
         .header("Content-Length", "0")
-        .header("Docker-Upload-UUID", parameters.uuid.to_string())
-        .body(RegistryResponseBody::empty())?;
+        .header("Docker-Upload-UUID", parameters.uuid.to_string());
 
-    Ok(res)
+    if let Some(chunk_min_length) = registry.upload_chunk_min_length() {
+        res = res.header("OCI-Chunk-Min-Length", chunk_min_length.to_string());
+    }
+
+    Ok(res.body(RegistryResponseBody::empty())?)
 }
 
 #[instrument(skip(request))]
@@ -320,7 +655,8 @@ pub async fn handle_put_upload(
     identity: ClientIdentity,
     parameters: UploadParameters,
 ) -> Result<Response<RegistryResponseBody>, RegistryError> {
-    identity.can_do(registry, ClientAction::PutBlob(parameters.name.clone()))?;
+    let action = ClientAction::PutBlob(parameters.name.clone());
+    identity.can_do(registry, action.clone())?;
 
     #[derive(Deserialize, Default)]
     struct CompleteUploadQuery {
@@ -331,9 +667,17 @@ pub async fn handle_put_upload(
     let digest = Digest::try_from(query.digest.as_str())?;
 
     let body = request.into_data_stream();
-    registry
-        .complete_upload(&parameters.name, parameters.uuid, digest.clone(), body)
-        .await?;
+    let res = registry
+        .complete_upload(
+            &parameters.name,
+            parameters.uuid,
+            digest.clone(),
+            body,
+            &identity,
+        )
+        .await;
+    registry.audit(&identity, &action, Some(&digest), &res);
+    res?;
 
     let location = format!("/v2/{}/blobs/{}", &parameters.name, digest);
 
@@ -396,14 +740,14 @@ pub async fn handle_delete_blob(
     identity: ClientIdentity,
     parameters: BlobParameters,
 ) -> Result<Response<RegistryResponseBody>, RegistryError> {
-    identity.can_do(
-        registry,
-        ClientAction::DeleteBlob(parameters.name.clone(), parameters.digest.clone()),
-    )?;
+    let action = ClientAction::DeleteBlob(parameters.name.clone(), parameters.digest.clone());
+    identity.can_do(registry, action.clone())?;
 
-    registry
+    let res = registry
         .delete_blob(&parameters.name, parameters.digest)
-        .await?;
+        .await;
+    registry.audit(&identity, &action, None, &res);
+    res?;
 
     let res = Response::builder()
         .status(StatusCode::ACCEPTED)
@@ -419,10 +763,8 @@ pub async fn handle_put_manifest(
     identity: ClientIdentity,
     parameters: ManifestParameters,
 ) -> Result<Response<RegistryResponseBody>, RegistryError> {
-    identity.can_do(
-        registry,
-        ClientAction::PutManifest(parameters.name.clone(), parameters.reference.clone()),
-    )?;
+    let action = ClientAction::PutManifest(parameters.name.clone(), parameters.reference.clone());
+    identity.can_do(registry, action.clone())?;
 
     let content_type = request
         .headers()
@@ -445,15 +787,20 @@ pub async fn handle_put_manifest(
         ))
     })?;
     let body = request_body.to_bytes();
+    METRICS.observe_received_bytes(body.len() as u64);
 
-    let manifest = registry
+    let res = registry
         .put_manifest(
             &parameters.name,
             parameters.reference.clone(),
             content_type,
             &body,
+            &identity,
         )
-        .await?;
+        .await;
+    let digest = res.as_ref().ok().map(|manifest| &manifest.digest);
+    registry.audit(&identity, &action, digest, &res);
+    let manifest = res?;
     let location = format!("/v2/{}/manifests/{}", parameters.name, parameters.reference);
 
     let res = match manifest.subject {
@@ -479,14 +826,16 @@ pub async fn handle_delete_manifest(
     identity: ClientIdentity,
     parameters: ManifestParameters,
 ) -> Result<Response<RegistryResponseBody>, RegistryError> {
-    identity.can_do(
-        registry,
-        ClientAction::DeleteManifest(parameters.name.clone(), parameters.reference.clone()),
-    )?;
-
-    registry
-        .delete_manifest(&parameters.name, parameters.reference)
-        .await?;
+    let action =
+        ClientAction::DeleteManifest(parameters.name.clone(), parameters.reference.clone());
+    identity.can_do(registry, action.clone())?;
+
+    let res = registry
+        .delete_manifest(&parameters.name, parameters.reference, &identity)
+        .await;
+    let digest = res.as_ref().ok().and_then(Option::as_ref);
+    registry.audit(&identity, &action, digest, &res);
+    res?;
 
     let res = Response::builder()
         .status(StatusCode::ACCEPTED)
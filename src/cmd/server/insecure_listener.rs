@@ -1,70 +1,137 @@
-use crate::cmd::server::serve_request;
+use crate::cmd::server::{
+    acquire_connection_permit, drain_connections, serve_request, HttpProtocol,
+};
 use crate::cmd::CommandError;
-use crate::configuration::Configuration;
+use crate::configuration::{Configuration, ServerHttp2Config};
 use crate::policy::ClientIdentity;
-use crate::registry::Registry;
+use crate::registry::{RateLimiter, Registry};
 use arc_swap::ArcSwap;
 use hyper_util::rt::TokioIo;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info};
 
 pub struct InsecureListener {
     binding_address: SocketAddr,
+    max_connections: usize,
+    connection_limit: Arc<Semaphore>,
+    http2_config: ArcSwap<ServerHttp2Config>,
     timeouts: ArcSwap<Vec<Duration>>,
+    shutdown_timeout: ArcSwap<Duration>,
     registry: ArcSwap<Registry>,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 impl InsecureListener {
     pub fn try_from_config(config: &Configuration) -> Result<Self, CommandError> {
         let binding_address = SocketAddr::new(config.server.bind_address, config.server.port);
+        let max_connections = config.server.max_concurrent_connections;
+        let connection_limit = Arc::new(Semaphore::new(max_connections));
+        let http2_config = ArcSwap::new(Arc::new(config.server.http2.clone()));
         let timeouts = ArcSwap::new(Arc::new(vec![
             Duration::from_secs(config.server.query_timeout),
             Duration::from_secs(config.server.query_timeout_grace_period),
         ]));
+        let shutdown_timeout = ArcSwap::new(Arc::new(Duration::from_secs(
+            config.server.shutdown_timeout,
+        )));
         let registry = ArcSwap::new(Arc::new(Registry::try_from_config(config)?));
+        let rate_limiter = Arc::new(RateLimiter::new(&config.rate_limit));
 
         Ok(Self {
             binding_address,
+            max_connections,
+            connection_limit,
+            http2_config,
             timeouts,
+            shutdown_timeout,
             registry,
+            rate_limiter,
         })
     }
 
     pub fn notify_config_change(&self, config: &Configuration) -> Result<(), CommandError> {
+        let http2_config = Arc::new(config.server.http2.clone());
         let timeouts = Arc::new(vec![
             Duration::from_secs(config.server.query_timeout),
             Duration::from_secs(config.server.query_timeout_grace_period),
         ]);
+        let shutdown_timeout = Arc::new(Duration::from_secs(config.server.shutdown_timeout));
         let registry = Arc::new(Registry::try_from_config(config)?);
 
+        self.http2_config.store(http2_config);
         self.timeouts.store(timeouts);
+        self.shutdown_timeout.store(shutdown_timeout);
         self.registry.store(registry);
+        self.rate_limiter.update(&config.rate_limit);
 
         Ok(())
     }
 
-    pub async fn serve(&self) -> Result<(), CommandError> {
+    pub async fn serve(&self, shutdown: CancellationToken) -> Result<(), CommandError> {
         info!("Listening on {} (non-TLS)", self.binding_address);
         let listener = TcpListener::bind(self.binding_address).await?;
 
         loop {
+            let permit = tokio::select! {
+                permit = acquire_connection_permit(&self.connection_limit) => permit?,
+                _ = shutdown.cancelled() => break,
+            };
+
             debug!("Waiting for incoming connection");
-            let (tcp, remote_address) = listener.accept().await?;
+            let (tcp, remote_address) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = shutdown.cancelled() => break,
+            };
 
             debug!("Accepted connection from {:?}", remote_address);
             let stream = TokioIo::new(tcp);
-            let timeouts = self.timeouts.load();
-            let registry = self.registry.load();
-            serve_request(
-                stream,
-                timeouts.clone(),
-                registry.clone(),
-                ClientIdentity::new(),
-            )
-            .await;
+            let http2_config = self.http2_config.load_full();
+            let timeouts = self.timeouts.load_full();
+            let registry = self.registry.load_full();
+            let rate_limiter = self.rate_limiter.clone();
+            let shutdown = shutdown.clone();
+
+            // Without TLS, there is no ALPN: HTTP/2 is only served to clients using
+            // prior knowledge (h2c), detected from the connection preface.
+            let protocol = if http2_config.enabled && http2_config.h2c {
+                HttpProtocol::Auto
+            } else {
+                HttpProtocol::Http1
+            };
+
+            let mut identity = ClientIdentity::new();
+            identity.set_client_ip(remote_address.ip());
+
+            tokio::spawn(async move {
+                serve_request(
+                    stream,
+                    protocol,
+                    http2_config,
+                    timeouts,
+                    registry,
+                    rate_limiter,
+                    identity,
+                    shutdown,
+                )
+                .await;
+                drop(permit);
+            });
         }
+
+        drop(listener);
+        let shutdown_timeout = **self.shutdown_timeout.load();
+        drain_connections(
+            &self.connection_limit,
+            self.max_connections,
+            shutdown_timeout,
+        )
+        .await;
+
+        Ok(())
     }
 }
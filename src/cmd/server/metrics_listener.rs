@@ -0,0 +1,82 @@
+use crate::cmd::server::handlers;
+use crate::cmd::CommandError;
+use crate::configuration::Configuration;
+use crate::error::RegistryError;
+use crate::registry::RegistryResponseBody;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::pin;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+// Serves the metrics endpoint on its own plaintext listener, so it can be kept private
+pub struct MetricsListener {
+    binding_address: SocketAddr,
+}
+
+impl MetricsListener {
+    pub fn from_config(config: &Configuration) -> Option<Self> {
+        let metrics_config = config.observability.as_ref()?.metrics.as_ref()?;
+        let port = metrics_config.port?;
+        let bind_address = metrics_config
+            .bind_address
+            .unwrap_or(config.server.bind_address);
+
+        Some(Self {
+            binding_address: SocketAddr::new(bind_address, port),
+        })
+    }
+
+    pub async fn serve(&self, shutdown: CancellationToken) -> Result<(), CommandError> {
+        info!("Serving metrics on {}", self.binding_address);
+        let listener = TcpListener::bind(self.binding_address).await?;
+
+        loop {
+            let (tcp, remote_address) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = shutdown.cancelled() => break,
+            };
+
+            debug!("Accepted metrics connection from {:?}", remote_address);
+            let shutdown = shutdown.clone();
+
+            tokio::spawn(async move {
+                let conn = http1::Builder::new()
+                    .serve_connection(TokioIo::new(tcp), service_fn(handle_metrics_request));
+                pin!(conn);
+
+                tokio::select! {
+                    res = conn.as_mut() => {
+                        if let Err(e) = res {
+                            debug!("Error serving metrics connection: {:?}", e);
+                        }
+                    }
+                    _ = shutdown.cancelled() => {
+                        conn.as_mut().graceful_shutdown();
+                        let _ = conn.await;
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+async fn handle_metrics_request(
+    request: Request<Incoming>,
+) -> Result<Response<RegistryResponseBody>, Infallible> {
+    let res = if request.method() == Method::GET && request.uri().path() == "/metrics" {
+        handlers::handle_get_metrics().await
+    } else {
+        Err(RegistryError::NotFound)
+    };
+
+    Ok(res.unwrap_or_else(|e| e.to_response()))
+}
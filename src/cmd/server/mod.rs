@@ -2,10 +2,10 @@ use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
 use hyper::body::Incoming;
 use hyper::header::HeaderValue;
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response};
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::Deserialize;
@@ -15,23 +15,28 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::pin;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, instrument, warn};
 use uuid::Uuid;
 
 mod handlers;
 mod insecure_listener;
+mod metrics_listener;
 mod params;
 mod tls_listener;
 
 use crate::cmd::error::CommandError;
 use crate::cmd::server::insecure_listener::InsecureListener;
+use crate::cmd::server::metrics_listener::MetricsListener;
 use crate::cmd::server::params::deserialize_params;
 use crate::cmd::server::tls_listener::TlsListener;
-use crate::configuration::Configuration;
+use crate::configuration::{Configuration, ServerHttp2Config};
 use crate::error::RegistryError;
+use crate::metrics::METRICS;
 use crate::oci::{Digest, Reference};
 use crate::policy::ClientIdentity;
-use crate::registry::{Registry, RegistryResponseBody};
+use crate::registry::{RateLimitClass, RateLimiter, Registry, RegistryResponseBody};
 
 lazy_static! {
     static ref ROUTE_API_VERSION_REGEX: Regex = Regex::new(r"^/v2/?$").unwrap();
@@ -47,9 +52,19 @@ lazy_static! {
         Regex::new(r"^/v2/(?P<name>.+)/referrers/(?P<digest>.+)$").unwrap();
     static ref ROUTE_LIST_TAGS_REGEX: Regex = Regex::new(r"^/v2/(?P<name>.+)/tags/list$").unwrap();
     static ref ROUTE_CATALOG_REGEX: Regex = Regex::new(r"^/v2/_catalog$").unwrap();
+    static ref ROUTE_TOKEN_REGEX: Regex = Regex::new(r"^/token/?$").unwrap();
+    static ref ROUTE_METRICS_REGEX: Regex = Regex::new(r"^/metrics$").unwrap();
+    static ref ROUTE_HEALTHZ_REGEX: Regex = Regex::new(r"^/healthz$").unwrap();
+    static ref ROUTE_READYZ_REGEX: Regex = Regex::new(r"^/readyz$").unwrap();
     static ref RANGE_RE: Regex = Regex::new(r"^(?:bytes=)?(?P<start>\d+)-(?P<end>\d+)$").unwrap();
 }
 
+#[derive(Debug, PartialEq)]
+pub enum Authorization {
+    Basic(String, String),
+    Bearer(String),
+}
+
 #[derive(Debug, Deserialize)]
 pub struct NewUploadParameters {
     pub name: String,
@@ -84,6 +99,13 @@ pub struct BlobParameters {
     pub digest: Digest,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub enum HttpProtocol {
+    Http1,
+    Http2,
+    Auto,
+}
+
 pub enum ServiceListener {
     Insecure(InsecureListener),
     Secure(TlsListener),
@@ -91,17 +113,27 @@ pub enum ServiceListener {
 
 pub struct Server {
     listener: ServiceListener,
+    metrics_listener: Option<MetricsListener>,
+    shutdown: CancellationToken,
 }
 
 impl Server {
     pub fn try_from_config(config: &Configuration) -> Result<Server, CommandError> {
+        if config.server.streaming_chunk_size.is_some() {
+            warn!("server.streaming_chunk_size is deprecated and ignored, use storage.s3.multipart_part_size instead");
+        }
+
         let listener = if config.server.tls.is_some() {
             ServiceListener::Secure(TlsListener::try_from_config(config)?)
         } else {
             ServiceListener::Insecure(InsecureListener::try_from_config(config)?)
         };
 
-        Ok(Server { listener })
+        Ok(Server {
+            listener,
+            metrics_listener: MetricsListener::from_config(config),
+            shutdown: CancellationToken::new(),
+        })
     }
 
     pub fn notify_config_change(&self, config: &Configuration) -> Result<(), CommandError> {
@@ -114,75 +146,196 @@ impl Server {
     }
 
     pub async fn run(&self) -> Result<(), CommandError> {
-        match &self.listener {
-            ServiceListener::Insecure(listener) => listener.serve().await?,
-            ServiceListener::Secure(listener) => listener.serve().await?,
-        }
+        let shutdown = self.shutdown.clone();
+        let service = async {
+            match &self.listener {
+                ServiceListener::Insecure(listener) => listener.serve(shutdown.clone()).await,
+                ServiceListener::Secure(listener) => listener.serve(shutdown.clone()).await,
+            }
+        };
 
+        let metrics = async {
+            match &self.metrics_listener {
+                Some(listener) => listener.serve(shutdown.clone()).await,
+                None => Ok(()),
+            }
+        };
+
+        tokio::try_join!(service, metrics)?;
         Ok(())
     }
+
+    pub fn shutdown(&self) {
+        info!("Shutting down: no longer accepting new connections");
+        self.shutdown.cancel();
+    }
+}
+
+async fn acquire_connection_permit(
+    connection_limit: &Arc<Semaphore>,
+) -> Result<OwnedSemaphorePermit, CommandError> {
+    if connection_limit.available_permits() == 0 {
+        warn!("Maximum number of concurrent connections reached, waiting for a free slot");
+    }
+
+    connection_limit
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|err| {
+            error!("Unable to acquire connection permit: {}", err);
+            CommandError::ConfigurationError("Connection limiter is closed".to_string())
+        })
+}
+
+async fn drain_connections(
+    connection_limit: &Semaphore,
+    max_connections: usize,
+    shutdown_timeout: Duration,
+) {
+    let active_connections = max_connections - connection_limit.available_permits();
+    if active_connections == 0 {
+        return;
+    }
+
+    info!(
+        "Waiting up to {:?} for {} active connection(s) to complete",
+        shutdown_timeout, active_connections
+    );
+
+    let Ok(permits) = u32::try_from(max_connections) else {
+        warn!("Unable to wait for {} connection(s)", max_connections);
+        return;
+    };
+
+    let all_permits = connection_limit.acquire_many(permits);
+    match tokio::time::timeout(shutdown_timeout, all_permits).await {
+        Ok(_) => info!("All connections drained"),
+        Err(_) => warn!(
+            "Shutdown timeout reached, dropping {} active connection(s)",
+            max_connections - connection_limit.available_permits()
+        ),
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn serve_request<S>(
     stream: TokioIo<S>,
+    protocol: HttpProtocol,
+    http2_config: Arc<ServerHttp2Config>,
     timeouts: Arc<Vec<Duration>>,
     registry: Arc<Registry>,
+    rate_limiter: Arc<RateLimiter>,
     identity: ClientIdentity,
+    shutdown: CancellationToken,
 ) where
     S: Unpin + AsyncWrite + AsyncRead + Send + Debug + 'static,
 {
-    let conn = http1::Builder::new().serve_connection(
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http2()
+        .max_concurrent_streams(http2_config.max_concurrent_streams)
+        .initial_stream_window_size(http2_config.initial_stream_window_size)
+        .initial_connection_window_size(http2_config.initial_connection_window_size)
+        .adaptive_window(http2_config.adaptive_window)
+        .max_frame_size(http2_config.max_frame_size);
+
+    debug!("Serving connection with protocol: {:?}", protocol);
+    let builder = match protocol {
+        HttpProtocol::Http1 => builder.http1_only(),
+        HttpProtocol::Http2 => builder.http2_only(),
+        HttpProtocol::Auto => builder,
+    };
+
+    let conn = builder.serve_connection(
         stream,
-        service_fn(move |request| handle_request(registry.clone(), request, identity.clone())),
+        service_fn(move |request| {
+            handle_request(
+                registry.clone(),
+                rate_limiter.clone(),
+                request,
+                identity.clone(),
+            )
+        }),
     );
     pin!(conn);
 
+    let mut shutdown_requested = false;
     for (iter, sleep_duration) in timeouts.iter().enumerate() {
         debug!("iter = {} sleep_duration = {:?}", iter, sleep_duration);
-        tokio::select! {
-            res = conn.as_mut() => {
-                // Polling the connection returned a result.
-                // In this case print either the successful or error result for the connection
-                // and break out of the loop.
-                match res {
-                    Ok(()) => debug!("after polling conn, no error"),
-                    Err(e) =>  debug!("error serving connection: {:?}", e),
-                };
-                break;
-            }
-            _ = tokio::time::sleep(*sleep_duration) => {
-                // tokio::time::sleep returned a result.
-                // Call graceful_shutdown on the connection and continue the loop.
-                debug!("iter = {} got timeout_interval, calling conn.graceful_shutdown", iter);
-                conn.as_mut().graceful_shutdown();
+        let sleep = tokio::time::sleep(*sleep_duration);
+        pin!(sleep);
+
+        loop {
+            tokio::select! {
+                res = conn.as_mut() => {
+                    // Polling the connection returned a result.
+                    // In this case print either the successful or error result for the connection
+                    // and return.
+                    match res {
+                        Ok(()) => debug!("after polling conn, no error"),
+                        Err(e) =>  debug!("error serving connection: {:?}", e),
+                    };
+                    return;
+                }
+                _ = shutdown.cancelled(), if !shutdown_requested => {
+                    // The server is shutting down.
+                    // Call graceful_shutdown on the connection so in-flight requests can complete,
+                    // and keep polling it.
+                    debug!("iter = {} got shutdown signal, calling conn.graceful_shutdown", iter);
+                    conn.as_mut().graceful_shutdown();
+                    shutdown_requested = true;
+                }
+                _ = &mut sleep => {
+                    // tokio::time::sleep returned a result.
+                    // Call graceful_shutdown on the connection and continue the loop.
+                    debug!("iter = {} got timeout_interval, calling conn.graceful_shutdown", iter);
+                    conn.as_mut().graceful_shutdown();
+                    break;
+                }
             }
         }
     }
 }
 
-#[instrument(skip(request))]
+#[instrument(skip(rate_limiter, request))]
 async fn handle_request(
     registry: Arc<Registry>,
+    rate_limiter: Arc<RateLimiter>,
     request: Request<Incoming>,
     identity: ClientIdentity,
 ) -> Result<Response<RegistryResponseBody>, Infallible> {
     let start_time = std::time::Instant::now();
     let method = request.method().to_string();
     let path = request.uri().path().to_string();
+    let route = get_route_name(&path);
     let error_level;
 
-    let response = match router(&registry, request, identity).await {
+    let response = match router(&registry, &rate_limiter, request, identity).await {
         Ok(res) => {
             error_level = false;
             Ok::<Response<RegistryResponseBody>, Infallible>(res)
         }
         Err(e) => {
             error_level = true;
-            Ok(e.to_response_with_span_id(tracing::Span::current().id()))
+            let mut res = e.to_response_with_span_id(tracing::Span::current().id());
+            if let (RegistryError::Unauthorized(_), Some(token_issuer)) =
+                (&e, &registry.token_issuer)
+            {
+                let challenge = token_issuer.challenge(get_token_scope(&method, &path));
+                if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                    res.headers_mut().insert("WWW-Authenticate", challenge);
+                }
+            }
+            Ok(res)
         }
     };
 
     let elapsed = start_time.elapsed();
+    if let Ok(response) = &response {
+        METRICS.observe_request(route, &method, response.status().as_u16(), elapsed);
+    }
+
     let status = response
         .as_ref()
         .map(|r| r.status().to_string())
@@ -203,27 +356,69 @@ async fn handle_request(
     response
 }
 
-#[instrument(skip(request))]
+#[instrument(skip(rate_limiter, request))]
 async fn router(
     registry: &Registry,
+    rate_limiter: &RateLimiter,
     request: Request<Incoming>,
     mut identity: ClientIdentity,
 ) -> Result<Response<RegistryResponseBody>, RegistryError> {
     let method = request.method().clone();
     let path = request.uri().path().to_string();
 
-    let basic_auth_credentials = request
+    // Probes are answered before looking at credentials, so they never require authentication
+    if ROUTE_HEALTHZ_REGEX.is_match(&path) {
+        if method == Method::GET {
+            return handlers::handle_get_healthz().await;
+        }
+        return Err(RegistryError::Unsupported);
+    } else if ROUTE_READYZ_REGEX.is_match(&path) {
+        if method == Method::GET {
+            return handlers::handle_get_readyz(registry).await;
+        }
+        return Err(RegistryError::Unsupported);
+    }
+
+    if let Some(peer_ip) = identity.client_ip {
+        identity.set_client_ip(registry.resolve_client_ip(peer_ip, request.headers()));
+    }
+    identity.set_headers(registry.select_policy_headers(request.headers()));
+
+    let authorization = request
         .headers()
         .get("Authorization")
         .and_then(parse_authorization_header);
 
-    debug!("Authorization: {:?}", basic_auth_credentials);
+    match authorization {
+        Some(Authorization::Basic(username, password)) => {
+            debug!("Authorization: Basic (username: {})", username);
+            identity.set_credentials(username, password);
+        }
+        Some(Authorization::Bearer(token)) => {
+            debug!("Authorization: Bearer");
+            identity.set_token(registry.validate_token(&token)?);
+        }
+        None => {}
+    }
 
-    if let Some((username, password)) = basic_auth_credentials {
-        identity.set_credentials(username, password);
+    if let Some(class) = get_rate_limit_class(&method, &path) {
+        if rate_limiter.is_enabled(class) {
+            rate_limiter.check(class, &identity.rate_limit_key(registry))?;
+        }
     }
 
-    if ROUTE_API_VERSION_REGEX.is_match(&path) {
+    if ROUTE_METRICS_REGEX.is_match(&path) && registry.expose_metrics {
+        if method == Method::GET {
+            return handlers::handle_get_metrics().await;
+        }
+        return Err(RegistryError::Unsupported);
+    } else if ROUTE_TOKEN_REGEX.is_match(&path) {
+        if method == Method::GET {
+            info!("Get token: {}", path);
+            return handlers::handle_get_token(registry, request, identity).await;
+        }
+        return Err(RegistryError::Unsupported);
+    } else if ROUTE_API_VERSION_REGEX.is_match(&path) {
         if method == Method::GET {
             info!("API version check: {}", path);
             return handlers::handle_get_api_version(registry, identity).await;
@@ -270,11 +465,11 @@ async fn router(
     } else if let Some(parameters) = deserialize_params(&path, &ROUTE_MANIFEST_REGEX) {
         if method == Method::GET {
             info!("Get manifest: {}", path);
-            return handlers::handle_get_manifest(registry, identity, parameters).await;
+            return handlers::handle_get_manifest(registry, request, identity, parameters).await;
         }
         if method == Method::HEAD {
             info!("Head manifest: {}", path);
-            return handlers::handle_head_manifest(registry, identity, parameters).await;
+            return handlers::handle_head_manifest(registry, request, identity, parameters).await;
         }
         if method == Method::PUT {
             info!("Put manifest: {}", path);
@@ -306,7 +501,89 @@ async fn router(
     Err(RegistryError::NotFound)
 }
 
-pub fn parse_authorization_header(header: &HeaderValue) -> Option<(String, String)> {
+// Names the route matched by the path, used as a low-cardinality metrics label
+pub fn get_route_name(path: &str) -> &'static str {
+    let routes = [
+        (&*ROUTE_API_VERSION_REGEX, "api_version"),
+        (&*ROUTE_METRICS_REGEX, "metrics"),
+        (&*ROUTE_HEALTHZ_REGEX, "healthz"),
+        (&*ROUTE_READYZ_REGEX, "readyz"),
+        (&*ROUTE_TOKEN_REGEX, "token"),
+        (&*ROUTE_UPLOADS_REGEX, "uploads"),
+        (&*ROUTE_UPLOAD_REGEX, "upload"),
+        (&*ROUTE_BLOB_REGEX, "blob"),
+        (&*ROUTE_MANIFEST_REGEX, "manifest"),
+        (&*ROUTE_REFERRERS_REGEX, "referrers"),
+        (&*ROUTE_CATALOG_REGEX, "catalog"),
+        (&*ROUTE_LIST_TAGS_REGEX, "tags"),
+    ];
+
+    routes
+        .iter()
+        .find(|(re, _)| re.is_match(path))
+        .map(|(_, name)| *name)
+        .unwrap_or("unknown")
+}
+
+// Computes the token scope a client needs to perform the request, used in the
+// `WWW-Authenticate` challenge when token authentication is enabled.
+pub fn get_token_scope(method: &str, path: &str) -> Option<String> {
+    if ROUTE_CATALOG_REGEX.is_match(path) {
+        return Some("registry:catalog:*".to_string());
+    }
+
+    let name = [
+        &*ROUTE_UPLOADS_REGEX,
+        &*ROUTE_UPLOAD_REGEX,
+        &*ROUTE_BLOB_REGEX,
+        &*ROUTE_MANIFEST_REGEX,
+        &*ROUTE_REFERRERS_REGEX,
+        &*ROUTE_LIST_TAGS_REGEX,
+    ]
+    .iter()
+    .find_map(|re| re.captures(path))
+    .and_then(|captures| captures.name("name").map(|name| name.as_str().to_string()))?;
+
+    let actions = match method {
+        "GET" | "HEAD" => "pull",
+        "POST" | "PUT" | "PATCH" => "pull,push",
+        "DELETE" => "delete",
+        _ => return None,
+    };
+
+    Some(format!("repository:{}:{}", name, actions))
+}
+
+// Classifies the request for rate limiting, requests that aren't classified are not limited.
+pub fn get_rate_limit_class(method: &Method, path: &str) -> Option<RateLimitClass> {
+    if ROUTE_CATALOG_REGEX.is_match(path) {
+        return Some(RateLimitClass::Catalog);
+    }
+
+    if ROUTE_UPLOADS_REGEX.is_match(path) || ROUTE_UPLOAD_REGEX.is_match(path) {
+        return Some(RateLimitClass::Push);
+    }
+
+    let is_repository_route = [
+        &*ROUTE_BLOB_REGEX,
+        &*ROUTE_MANIFEST_REGEX,
+        &*ROUTE_REFERRERS_REGEX,
+        &*ROUTE_LIST_TAGS_REGEX,
+    ]
+    .iter()
+    .any(|re| re.is_match(path));
+
+    if !is_repository_route {
+        return None;
+    }
+
+    match *method {
+        Method::GET | Method::HEAD => Some(RateLimitClass::Pull),
+        _ => Some(RateLimitClass::Push),
+    }
+}
+
+pub fn parse_authorization_header(header: &HeaderValue) -> Option<Authorization> {
     let Ok(header_str) = header.to_str() else {
         debug!("Error parsing Authorization header as string");
         return None;
@@ -314,10 +591,14 @@ pub fn parse_authorization_header(header: &HeaderValue) -> Option<(String, Strin
 
     let parts: Vec<&str> = header_str.split_whitespace().collect();
     if parts.len() != 2 {
-        debug!("Invalid Authorization header format: {}", header_str);
+        debug!("Invalid Authorization header format");
         return None;
     }
 
+    if parts[0] == "Bearer" {
+        return Some(Authorization::Bearer(parts[1].to_string()));
+    }
+
     if parts[0] != "Basic" {
         debug!("Invalid Authorization header type: {}", parts[0]);
         return None;
@@ -335,9 +616,12 @@ pub fn parse_authorization_header(header: &HeaderValue) -> Option<(String, Strin
 
     let parts: Vec<&str> = auth_str.splitn(2, ':').collect();
     if parts.len() != 2 {
-        warn!("Invalid Authorization header format: {}", auth_str);
+        warn!("Invalid Authorization header format");
         return None;
     }
 
-    Some((parts[0].to_string(), parts[1].to_string()))
+    Some(Authorization::Basic(
+        parts[0].to_string(),
+        parts[1].to_string(),
+    ))
 }
@@ -1,8 +1,10 @@
-use crate::cmd::server::serve_request;
+use crate::cmd::server::{
+    acquire_connection_permit, drain_connections, serve_request, HttpProtocol,
+};
 use crate::cmd::CommandError;
-use crate::configuration::{Configuration, ServerTlsConfig};
+use crate::configuration::{Configuration, ServerHttp2Config, ServerTlsConfig};
 use crate::policy::ClientIdentity;
-use crate::registry::Registry;
+use crate::registry::{RateLimiter, Registry};
 use arc_swap::ArcSwap;
 use hyper_util::rt::TokioIo;
 use rustls::server::WebPkiClientVerifier;
@@ -15,16 +17,24 @@ use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 use tokio_rustls::TlsAcceptor;
-use tracing::{debug, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
 use x509_parser::certificate::X509Certificate;
 use x509_parser::prelude::FromDer;
 
 pub struct TlsListener {
     binding_address: SocketAddr,
+    max_connections: usize,
+    connection_limit: Arc<Semaphore>,
+    http2_config: ArcSwap<ServerHttp2Config>,
     timeouts: ArcSwap<Vec<Duration>>,
+    shutdown_timeout: ArcSwap<Duration>,
+    tls_handshake_timeout: ArcSwap<Duration>,
     tls_acceptor: ArcSwap<TlsAcceptor>,
     registry: ArcSwap<Registry>,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 impl TlsListener {
@@ -34,18 +44,37 @@ impl TlsListener {
         })?;
 
         let binding_address = SocketAddr::new(config.server.bind_address, config.server.port);
+        let max_connections = config.server.max_concurrent_connections;
+        let connection_limit = Arc::new(Semaphore::new(max_connections));
+        let http2_config = ArcSwap::new(Arc::new(config.server.http2.clone()));
         let timeouts = ArcSwap::new(Arc::new(vec![
             Duration::from_secs(config.server.query_timeout),
             Duration::from_secs(config.server.query_timeout_grace_period),
         ]));
-        let tls_acceptor = ArcSwap::new(Arc::new(Self::build_tls_acceptor(tls_config)?));
+        let shutdown_timeout = ArcSwap::new(Arc::new(Duration::from_secs(
+            config.server.shutdown_timeout,
+        )));
+        let tls_handshake_timeout = ArcSwap::new(Arc::new(Duration::from_secs(
+            config.server.tls_handshake_timeout,
+        )));
+        let tls_acceptor = ArcSwap::new(Arc::new(Self::build_tls_acceptor(
+            tls_config,
+            &config.server.http2,
+        )?));
         let registry = ArcSwap::new(Arc::new(Registry::try_from_config(config)?));
+        let rate_limiter = Arc::new(RateLimiter::new(&config.rate_limit));
 
         Ok(Self {
             binding_address,
+            max_connections,
+            connection_limit,
+            http2_config,
             timeouts,
+            shutdown_timeout,
+            tls_handshake_timeout,
             tls_acceptor,
             registry,
+            rate_limiter,
         })
     }
 
@@ -54,27 +83,38 @@ impl TlsListener {
             CommandError::ConfigurationError("TLS configuration is missing".to_string())
         })?;
 
+        let http2_config = Arc::new(config.server.http2.clone());
         let timeouts = Arc::new(vec![
             Duration::from_secs(config.server.query_timeout),
             Duration::from_secs(config.server.query_timeout_grace_period),
         ]);
-        let tls_acceptor = Arc::new(Self::build_tls_acceptor(tls_config)?);
+        let shutdown_timeout = Arc::new(Duration::from_secs(config.server.shutdown_timeout));
+        let tls_handshake_timeout =
+            Arc::new(Duration::from_secs(config.server.tls_handshake_timeout));
+        let tls_acceptor = Arc::new(Self::build_tls_acceptor(tls_config, &http2_config)?);
         let registry = Arc::new(Registry::try_from_config(config)?);
 
+        self.http2_config.store(http2_config);
         self.timeouts.store(timeouts);
+        self.shutdown_timeout.store(shutdown_timeout);
+        self.tls_handshake_timeout.store(tls_handshake_timeout);
         self.tls_acceptor.store(tls_acceptor);
         self.registry.store(registry);
+        self.rate_limiter.update(&config.rate_limit);
 
         Ok(())
     }
 
-    fn build_tls_acceptor(tls_config: &ServerTlsConfig) -> Result<TlsAcceptor, CommandError> {
+    fn build_tls_acceptor(
+        tls_config: &ServerTlsConfig,
+        http2_config: &ServerHttp2Config,
+    ) -> Result<TlsAcceptor, CommandError> {
         debug!("Detected TLS configuration");
         let server_certs =
             Self::load_certificate_bundle(tls_config.server_certificate_bundle.as_str())?;
         let server_key = Self::load_private_key(tls_config.server_private_key.as_str())?;
 
-        let server_config = match tls_config.client_ca_bundle.as_ref() {
+        let mut server_config = match tls_config.client_ca_bundle.as_ref() {
             Some(client_ca_bundle) => {
                 debug!("Client CA bundle detected (will serve with TLS client authentication)");
                 let client_cert = Self::load_certificate_bundle(client_ca_bundle)?;
@@ -100,6 +140,13 @@ impl TlsListener {
             }
         };
 
+        server_config.alpn_protocols = if http2_config.enabled {
+            debug!("HTTP/2 enabled (will negotiate h2 and http/1.1 with ALPN)");
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+
         let server_config = Arc::new(server_config);
         let tls_acceptor = TlsAcceptor::from(server_config);
         Ok(tls_acceptor)
@@ -129,40 +176,89 @@ impl TlsListener {
         Ok(root_store)
     }
 
-    pub async fn serve(&self) -> Result<(), CommandError> {
+    pub async fn serve(&self, shutdown: CancellationToken) -> Result<(), CommandError> {
         info!("Listening on {} (mTLS)", self.binding_address);
         let listener = TcpListener::bind(self.binding_address).await?;
 
         loop {
-            let (tcp, remote_address) = listener.accept().await?;
+            let permit = tokio::select! {
+                permit = acquire_connection_permit(&self.connection_limit) => permit?,
+                _ = shutdown.cancelled() => break,
+            };
+
+            let (tcp, remote_address) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = shutdown.cancelled() => break,
+            };
+
+            let tls_acceptor = self.tls_acceptor.load_full();
+            let tls_handshake_timeout = **self.tls_handshake_timeout.load();
+            let http2_config = self.http2_config.load_full();
+            let timeouts = self.timeouts.load_full();
+            let registry = self.registry.load_full();
+            let rate_limiter = self.rate_limiter.clone();
+            let shutdown = shutdown.clone();
+
+            tokio::spawn(async move {
+                let tls_stream =
+                    tokio::time::timeout(tls_handshake_timeout, tls_acceptor.accept(tcp)).await;
+                drop(tls_acceptor);
 
-            let tls_acceptor = self.tls_acceptor.load();
-            let tls_stream = tls_acceptor.accept(tcp).await;
-            drop(tls_acceptor);
+                let tls = match tls_stream {
+                    Ok(Ok(tls)) => tls,
+                    Ok(Err(err)) => {
+                        debug!("TLS handshake failed with {:?}: {}", remote_address, err);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("TLS handshake timed out with {:?}", remote_address);
+                        return;
+                    }
+                };
 
-            if let Ok(tls) = tls_stream {
                 let (_, session) = tls.get_ref();
 
-                let identity = session
+                let protocol = match session.alpn_protocol() {
+                    Some(b"h2") => HttpProtocol::Http2,
+                    _ => HttpProtocol::Http1,
+                };
+
+                let mut identity = session
                     .peer_certificates()
                     .and_then(|certs| certs.first())
                     .and_then(|cert| match X509Certificate::from_der(cert).ok() {
                         Some((_, cert)) => ClientIdentity::from_cert(&cert).ok(),
                         None => None,
-                    });
+                    })
+                    .unwrap_or_default();
+                identity.set_client_ip(remote_address.ip());
 
                 debug!("Accepted connection from {:?}", remote_address);
                 let stream = TokioIo::new(tls);
-                let timeouts = self.timeouts.load();
-                let registry = self.registry.load();
                 serve_request(
                     stream,
-                    timeouts.clone(),
-                    registry.clone(),
-                    identity.unwrap_or_default(),
+                    protocol,
+                    http2_config,
+                    timeouts,
+                    registry,
+                    rate_limiter,
+                    identity,
+                    shutdown,
                 )
                 .await;
-            }
+                drop(permit);
+            });
         }
+
+        drop(listener);
+        let shutdown_timeout = **self.shutdown_timeout.load();
+        drain_connections(
+            &self.connection_limit,
+            self.max_connections,
+            shutdown_timeout,
+        )
+        .await;
+
+        Ok(())
     }
 }
@@ -1,26 +1,34 @@
 use crate::error::RegistryError;
 use crate::lock_manager::LockManager;
-use crate::storage::{FileSystemStorageEngine, S3StorageEngine, StorageEngine};
+use crate::policy::TokenIssuer;
+use crate::registry::{AuditLogger, EventAction, Notifier, RepositoryPattern};
+use crate::storage::{
+    FileSystemStorageEngine, MeteredStorageEngine, S3StorageEngine, StorageEngine,
+};
 use cel_interpreter::Program;
-use lazy_static::lazy_static;
+use hyper::header::HeaderName;
+use ipnet::IpNet;
 use regex::Regex;
 use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
 use std::fs;
 use std::net::IpAddr;
 use std::path::Path;
+use tokio::sync::Semaphore;
 use tracing::{debug, error, info};
 
 mod data_size;
 
 pub use data_size::DataSize;
 
-lazy_static! {
-    // This regex is used to validate repository names.
-    // We choose to have the same constraints as namespaces initial part.
-    static ref REPOSITORY_RE: Regex =
-        Regex::new(r"^[a-z0-9]+(?:[._-][a-z0-9]+)*$").unwrap();
-}
+// Layers hosted outside of the registry, which clients never push
+const FOREIGN_LAYER_MEDIA_TYPES: [&str; 4] = [
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd",
+];
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Configuration {
@@ -30,8 +38,18 @@ pub struct Configuration {
     #[serde(default)]
     pub identity: HashMap<String, IdentityConfig>, // hashmap of identity_id <-> identity_config (username, password)
     #[serde(default)]
+    pub token: Option<TokenConfig>,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub global: Option<GlobalConfig>,
+    #[serde(default)]
     pub repository: Vec<RepositoryConfig>,
     #[serde(default)]
+    pub webhook: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+    #[serde(default)]
     pub observability: Option<ObservabilityConfig>,
 }
 
@@ -44,8 +62,30 @@ pub struct ServerConfig {
     #[serde(default = "ServerConfig::default_query_timeout_grace_period")]
     pub query_timeout_grace_period: u64,
     pub tls: Option<ServerTlsConfig>,
-    #[serde(default = "ServerConfig::default_streaming_chunk_size")]
-    pub streaming_chunk_size: DataSize,
+    #[serde(default)]
+    pub http2: ServerHttp2Config,
+    // Deprecated and ignored: uploads are streamed, S3 parts are sized by
+    // `storage.s3.multipart_part_size`
+    #[serde(default)]
+    pub streaming_chunk_size: Option<DataSize>,
+    #[serde(default = "ServerConfig::default_max_concurrent_connections")]
+    pub max_concurrent_connections: usize,
+    #[serde(default = "ServerConfig::default_tls_handshake_timeout")]
+    pub tls_handshake_timeout: u64,
+    #[serde(default = "ServerConfig::default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default = "ServerConfig::default_credentials_cache_ttl")]
+    pub credentials_cache_ttl: u64,
+    #[serde(default = "ServerConfig::default_credentials_cache_size")]
+    pub credentials_cache_size: usize,
+    #[serde(default)]
+    pub global_blob_access: bool,
+    #[serde(default)]
+    pub foreign_layer_media_types: Vec<String>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default = "ServerConfig::default_policy_headers")]
+    pub policy_headers: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -75,8 +115,28 @@ impl ServerConfig {
         60
     }
 
-    fn default_streaming_chunk_size() -> DataSize {
-        DataSize::WithUnit(50, "MiB".to_string())
+    fn default_max_concurrent_connections() -> usize {
+        1024
+    }
+
+    fn default_tls_handshake_timeout() -> u64 {
+        10
+    }
+
+    fn default_shutdown_timeout() -> u64 {
+        30
+    }
+
+    fn default_credentials_cache_ttl() -> u64 {
+        60
+    }
+
+    fn default_policy_headers() -> Vec<String> {
+        vec!["user-agent".to_string()]
+    }
+
+    fn default_credentials_cache_size() -> usize {
+        1024
     }
 }
 
@@ -87,6 +147,48 @@ pub struct ServerTlsConfig {
     pub client_ca_bundle: Option<String>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServerHttp2Config {
+    #[serde(default = "ServerHttp2Config::default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub h2c: bool,
+    #[serde(default = "ServerHttp2Config::default_max_concurrent_streams")]
+    pub max_concurrent_streams: u32,
+    #[serde(default)]
+    pub initial_stream_window_size: Option<u32>,
+    #[serde(default)]
+    pub initial_connection_window_size: Option<u32>,
+    #[serde(default)]
+    pub adaptive_window: bool,
+    #[serde(default)]
+    pub max_frame_size: Option<u32>,
+}
+
+impl ServerHttp2Config {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_max_concurrent_streams() -> u32 {
+        200
+    }
+}
+
+impl Default for ServerHttp2Config {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            h2c: false,
+            max_concurrent_streams: Self::default_max_concurrent_streams(),
+            initial_stream_window_size: None,
+            initial_connection_window_size: None,
+            adaptive_window: false,
+            max_frame_size: None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct StorageConfig {
     #[serde(flatten)]
@@ -129,6 +231,8 @@ pub struct StorageS3Config {
     pub multipart_copy_jobs: usize,
     #[serde(default = "StorageS3Config::default_multipart_min_part_size")]
     pub multipart_min_part_size: DataSize,
+    #[serde(default = "StorageS3Config::default_multipart_part_size")]
+    pub multipart_part_size: DataSize,
 }
 
 impl StorageS3Config {
@@ -146,6 +250,10 @@ impl StorageS3Config {
     fn default_multipart_min_part_size() -> DataSize {
         DataSize::WithUnit(5, "MB".to_string())
     }
+
+    fn default_multipart_part_size() -> DataSize {
+        DataSize::WithUnit(50, "MiB".to_string())
+    }
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -154,18 +262,193 @@ pub struct IdentityConfig {
     pub password: String,
 }
 
+#[derive(Clone, Deserialize)]
+pub struct TokenConfig {
+    pub realm: String,
+    pub service: String,
+    #[serde(default = "TokenConfig::default_issuer")]
+    pub issuer: String,
+    pub secret: String,
+    #[serde(default = "TokenConfig::default_ttl")]
+    pub ttl: u64,
+}
+
+impl Debug for TokenConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenConfig")
+            .field("realm", &self.realm)
+            .field("service", &self.service)
+            .field("issuer", &self.issuer)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl TokenConfig {
+    fn default_issuer() -> String {
+        "origin".to_string()
+    }
+
+    fn default_ttl() -> u64 {
+        300
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub pull: Option<RateLimitRuleConfig>,
+    #[serde(default)]
+    pub push: Option<RateLimitRuleConfig>,
+    #[serde(default)]
+    pub catalog: Option<RateLimitRuleConfig>,
+    #[serde(default = "RateLimitConfig::default_max_tracked_clients")]
+    pub max_tracked_clients: usize,
+}
+
+impl RateLimitConfig {
+    fn default_max_tracked_clients() -> usize {
+        10000
+    }
+
+    fn rules(&self) -> impl Iterator<Item = &RateLimitRuleConfig> {
+        [&self.pull, &self.push, &self.catalog]
+            .into_iter()
+            .flatten()
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            pull: None,
+            push: None,
+            catalog: None,
+            max_tracked_clients: Self::default_max_tracked_clients(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitRuleConfig {
+    #[serde(default)]
+    pub per_identity: Option<TokenBucketConfig>,
+    #[serde(default)]
+    pub global: Option<TokenBucketConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TokenBucketConfig {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GlobalConfig {
+    pub policy_default_allow: bool,
+    #[serde(default)]
+    pub policies: Vec<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct RepositoryConfig {
     pub namespace: String,
     pub policy_default_allow: bool,
     #[serde(default)]
     pub policies: Vec<String>,
+    #[serde(default = "RepositoryConfig::default_inherit_policies")]
+    pub inherit_policies: bool,
+    #[serde(default)]
+    pub immutable_tags: Vec<String>,
+}
+
+impl RepositoryConfig {
+    fn default_inherit_policies() -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct WebhookConfig {
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default = "WebhookConfig::default_actions")]
+    pub actions: Vec<String>,
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    #[serde(default = "WebhookConfig::default_timeout")]
+    pub timeout: u64,
+    #[serde(default = "WebhookConfig::default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "WebhookConfig::default_retry_interval")]
+    pub retry_interval: u64,
+    #[serde(default = "WebhookConfig::default_queue_size")]
+    pub queue_size: usize,
+}
+
+impl Debug for WebhookConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Headers are not printed, they usually carry credentials
+        f.debug_struct("WebhookConfig")
+            .field("name", &self.name)
+            .field("url", &self.url)
+            .field("headers", &self.headers.len())
+            .field("actions", &self.actions)
+            .field("repositories", &self.repositories)
+            .field("timeout", &self.timeout)
+            .field("max_retries", &self.max_retries)
+            .field("retry_interval", &self.retry_interval)
+            .field("queue_size", &self.queue_size)
+            .finish()
+    }
+}
+
+impl WebhookConfig {
+    fn default_actions() -> Vec<String> {
+        vec!["push".to_string(), "delete".to_string()]
+    }
+
+    fn default_timeout() -> u64 {
+        5
+    }
+
+    fn default_max_retries() -> u32 {
+        5
+    }
+
+    fn default_retry_interval() -> u64 {
+        1
+    }
+
+    fn default_queue_size() -> usize {
+        1024
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuditConfig {
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub include_denied: bool,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct ObservabilityConfig {
     #[serde(default)]
     pub tracing: Option<TracingConfig>,
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+    #[serde(default)]
+    pub port: Option<u16>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -174,17 +457,110 @@ pub struct TracingConfig {
     pub sampling_rate: f64,
 }
 
+// Trusted proxies are given as CIDR ranges, or as single addresses
+fn parse_trusted_proxy(proxy: &str) -> Option<IpNet> {
+    proxy
+        .parse::<IpNet>()
+        .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
 impl Configuration {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RegistryError> {
         let config_str = fs::read_to_string(path)?;
         let config: Self = toml::from_str(&config_str)?;
 
-        if config.server.streaming_chunk_size.as_bytes() < 5 * 1024 * 1024 {
+        if config.server.max_concurrent_connections == 0 {
             return Err(RegistryError::InternalServerError(Some(
-                "Streaming chunk size must be at least 5MiB".to_string(),
+                "Maximum concurrent connections must be at least 1".to_string(),
             )));
         }
 
+        // Connections are limited by a semaphore, drained all at once on shutdown
+        let max_connections = Semaphore::MAX_PERMITS.min(u32::MAX as usize);
+        if config.server.max_concurrent_connections > max_connections {
+            return Err(RegistryError::InternalServerError(Some(format!(
+                "Maximum concurrent connections must be at most {}",
+                max_connections
+            ))));
+        }
+
+        if let StorageBackendConfig::S3(s3_config) = &config.storage.backend {
+            // Blob uploads are split in parts of this size, S3 allows at most 10,000 parts
+            if s3_config.multipart_part_size.as_bytes()
+                < s3_config.multipart_min_part_size.as_bytes()
+            {
+                return Err(RegistryError::InternalServerError(Some(
+                    "S3 multipart part size must be at least the minimum part size".to_string(),
+                )));
+            }
+        }
+
+        if let Some(proxy) = config
+            .server
+            .trusted_proxies
+            .iter()
+            .find(|proxy| parse_trusted_proxy(proxy).is_none())
+        {
+            return Err(RegistryError::InternalServerError(Some(format!(
+                "Invalid trusted proxy '{}' (expected an IP address or a CIDR range)",
+                proxy
+            ))));
+        }
+
+        if let Some(header) = config
+            .server
+            .policy_headers
+            .iter()
+            .find(|header| HeaderName::from_bytes(header.as_bytes()).is_err())
+        {
+            return Err(RegistryError::InternalServerError(Some(format!(
+                "Invalid policy header name '{}'",
+                header
+            ))));
+        }
+
+        let buckets = config
+            .rate_limit
+            .rules()
+            .flat_map(|rule| [&rule.per_identity, &rule.global])
+            .flatten();
+        for bucket in buckets {
+            if !bucket.rate.is_finite() || bucket.rate <= 0.0 || bucket.burst == 0 {
+                return Err(RegistryError::InternalServerError(Some(
+                    "Rate limit rate and burst must be greater than 0".to_string(),
+                )));
+            }
+        }
+
+        for webhook in &config.webhook {
+            if let Some(action) = webhook
+                .actions
+                .iter()
+                .find(|action| EventAction::from_name(action).is_none())
+            {
+                return Err(RegistryError::InternalServerError(Some(format!(
+                    "Webhook '{}' has an invalid action '{}' (expected push, pull or delete)",
+                    webhook.name, action
+                ))));
+            }
+
+            if webhook.queue_size == 0 {
+                return Err(RegistryError::InternalServerError(Some(format!(
+                    "Webhook '{}' queue size must be at least 1",
+                    webhook.name
+                ))));
+            }
+        }
+
+        if let Some(token) = &config.token {
+            if token.secret.len() < 32 {
+                return Err(RegistryError::InternalServerError(Some(
+                    "Token secret must be at least 32 bytes long".to_string(),
+                )));
+            }
+        }
+
         Ok(config)
     }
 
@@ -204,16 +580,21 @@ impl Configuration {
     }
 
     pub fn build_storage_engine(&self) -> Result<Box<dyn StorageEngine>, RegistryError> {
-        match &self.storage.backend {
-            StorageBackendConfig::FS(fs_config) => Ok(Box::new(FileSystemStorageEngine::new(
-                fs_config.root_dir.clone(),
-                self.build_lock_manager()?,
-            ))),
-            StorageBackendConfig::S3(s3_config) => Ok(Box::new(S3StorageEngine::new(
-                s3_config,
-                self.build_lock_manager()?,
-            )?)),
-        }
+        let (backend, storage): (_, Box<dyn StorageEngine>) = match &self.storage.backend {
+            StorageBackendConfig::FS(fs_config) => (
+                "fs",
+                Box::new(FileSystemStorageEngine::new(
+                    fs_config.root_dir.clone(),
+                    self.build_lock_manager()?,
+                )),
+            ),
+            StorageBackendConfig::S3(s3_config) => (
+                "s3",
+                Box::new(S3StorageEngine::new(s3_config, self.build_lock_manager()?)?),
+            ),
+        };
+
+        Ok(Box::new(MeteredStorageEngine::new(backend, storage)))
     }
 
     pub fn build_credentials(&self) -> HashMap<String, (String, String)> {
@@ -227,16 +608,85 @@ impl Configuration {
         credentials_map
     }
 
-    pub fn build_repositories_list(&self) -> HashSet<String> {
-        let mut namespace_set = HashSet::new();
+    pub fn build_token_issuer(&self) -> Option<TokenIssuer> {
+        self.token.as_ref().map(TokenIssuer::new)
+    }
+
+    pub fn build_trusted_proxies(&self) -> Vec<IpNet> {
+        self.server
+            .trusted_proxies
+            .iter()
+            .filter_map(|proxy| parse_trusted_proxy(proxy))
+            .collect()
+    }
+
+    pub fn build_policy_headers(&self) -> Vec<HeaderName> {
+        self.server
+            .policy_headers
+            .iter()
+            .filter_map(|header| HeaderName::from_bytes(header.as_bytes()).ok())
+            .collect()
+    }
+
+    // Configured media types extend the standard foreign and non-distributable ones
+    pub fn build_foreign_layer_media_types(&self) -> HashSet<String> {
+        FOREIGN_LAYER_MEDIA_TYPES
+            .iter()
+            .map(|media_type| media_type.to_string())
+            .chain(self.server.foreign_layer_media_types.iter().cloned())
+            .collect()
+    }
+
+    // Metrics are served by the registry listener, unless a dedicated port is configured
+    pub fn build_expose_metrics(&self) -> bool {
+        self.observability
+            .as_ref()
+            .and_then(|observability| observability.metrics.as_ref())
+            .is_some_and(|metrics| metrics.port.is_none())
+    }
+
+    pub fn build_notifier(&self) -> Result<Notifier, RegistryError> {
+        Notifier::new(&self.webhook)
+    }
+
+    pub fn build_audit_logger(&self) -> Result<AuditLogger, RegistryError> {
+        AuditLogger::new(self.audit.as_ref())
+    }
+
+    pub fn build_global_policy_default_allow(&self) -> bool {
+        self.global
+            .as_ref()
+            .map(|global| global.policy_default_allow)
+            .unwrap_or(true)
+    }
+
+    pub fn build_global_policies(&self) -> Result<Vec<Program>, RegistryError> {
+        let Some(global) = &self.global else {
+            return Ok(Vec::new());
+        };
+
+        let mut policies = Vec::new();
+        for policy in &global.policies {
+            debug!("Compiling global policy: {}", policy);
+            let program = Program::compile(policy)?;
+            policies.push(program);
+        }
+
+        debug!("Compiled {} global policies", policies.len());
+        Ok(policies)
+    }
+
+    pub fn build_repositories_list(&self) -> Vec<RepositoryPattern> {
+        let mut repositories = Vec::new();
         for repo in self.repository.iter() {
-            if !REPOSITORY_RE.is_match(&repo.namespace) {
+            let Some(pattern) = RepositoryPattern::parse(&repo.namespace) else {
                 error!("Invalid repository name: {}", repo.namespace);
                 continue;
-            }
-            namespace_set.insert(repo.namespace.clone());
+            };
+            repositories.push(pattern);
         }
-        namespace_set
+        repositories.sort_by(RepositoryPattern::cmp_specificity);
+        repositories
     }
 
     pub fn build_repository_default_allow_list(&self) -> HashMap<String, bool> {
@@ -247,6 +697,41 @@ impl Configuration {
         policy_default_allow_map
     }
 
+    pub fn build_repository_inherit_policies_list(&self) -> HashMap<String, bool> {
+        let mut inherit_policies_map = HashMap::new();
+        for repo in self.repository.iter() {
+            inherit_policies_map.insert(repo.namespace.clone(), repo.inherit_policies);
+        }
+        inherit_policies_map
+    }
+
+    // Patterns must match whole tags, e.g. `v\d+\.\d+\.\d+` doesn't match `v1.2.3-rc1`
+    pub fn build_repository_immutable_tags(
+        &self,
+    ) -> Result<HashMap<String, Vec<Regex>>, RegistryError> {
+        let mut immutable_tags_map = HashMap::new();
+        for repo in &self.repository {
+            let mut patterns = Vec::new();
+
+            for pattern in &repo.immutable_tags {
+                let regex = Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+                    error!(
+                        "Invalid immutable tag pattern '{}' for namespace {}: {}",
+                        pattern, repo.namespace, e
+                    );
+                    RegistryError::InternalServerError(Some(
+                        "Invalid immutable tag pattern".to_string(),
+                    ))
+                })?;
+                patterns.push(regex);
+            }
+
+            immutable_tags_map.insert(repo.namespace.clone(), patterns);
+        }
+
+        Ok(immutable_tags_map)
+    }
+
     pub fn build_repository_policies(
         &self,
     ) -> Result<HashMap<String, Vec<Program>>, RegistryError> {
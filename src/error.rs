@@ -18,7 +18,7 @@ pub enum RegistryError {
     BlobUploadInvalid,
     BlobUploadUnknown,
     DigestInvalid,
-    ManifestBlobUnknown,
+    ManifestBlobUnknown(Vec<String>),
     ManifestInvalid(Option<String>),
     ManifestUnknown,
     NameInvalid,
@@ -27,7 +27,7 @@ pub enum RegistryError {
     Unauthorized(Option<String>),
     Denied,
     Unsupported,
-    TooManyRequests,
+    TooManyRequests(Option<u64>),
     // Convenience
     RangeNotSatisfiable,
     // Catch-all
@@ -45,7 +45,9 @@ impl RegistryError {
             RegistryError::BlobUploadInvalid => (StatusCode::BAD_REQUEST, "BLOB_UPLOAD_INVALID"),
             RegistryError::BlobUploadUnknown => (StatusCode::NOT_FOUND, "BLOB_UPLOAD_UNKNOWN"),
             RegistryError::DigestInvalid => (StatusCode::BAD_REQUEST, "DIGEST_INVALID"),
-            RegistryError::ManifestBlobUnknown => (StatusCode::NOT_FOUND, "MANIFEST_BLOB_UNKNOWN"),
+            RegistryError::ManifestBlobUnknown(_) => {
+                (StatusCode::NOT_FOUND, "MANIFEST_BLOB_UNKNOWN")
+            }
             RegistryError::ManifestInvalid(_) => (StatusCode::BAD_REQUEST, "MANIFEST_INVALID"),
             RegistryError::ManifestUnknown => (StatusCode::NOT_FOUND, "MANIFEST_UNKNOWN"),
             RegistryError::NameInvalid => (StatusCode::BAD_REQUEST, "NAME_INVALID"),
@@ -54,7 +56,7 @@ impl RegistryError {
             RegistryError::Unauthorized(_) => (StatusCode::UNAUTHORIZED, "UNAUTHORIZED"),
             RegistryError::Denied => (StatusCode::FORBIDDEN, "DENIED"),
             RegistryError::Unsupported => (StatusCode::BAD_REQUEST, "UNSUPPORTED"),
-            RegistryError::TooManyRequests => (StatusCode::TOO_MANY_REQUESTS, "TOOMANYREQUESTS"),
+            RegistryError::TooManyRequests(_) => (StatusCode::TOO_MANY_REQUESTS, "TOOMANYREQUESTS"),
             // Convenience
             RegistryError::RangeNotSatisfiable => {
                 (StatusCode::RANGE_NOT_SATISFIABLE, "SIZE_INVALID")
@@ -66,6 +68,18 @@ impl RegistryError {
             }
         };
 
+        let mut details = serde_json::to_value(details).unwrap_or_default();
+        if let RegistryError::ManifestBlobUnknown(digests) = self {
+            if !digests.is_empty() {
+                match details {
+                    serde_json::Value::Object(ref mut details) => {
+                        details.insert("digests".to_string(), json!(digests));
+                    }
+                    _ => details = json!({ "digests": digests }),
+                }
+            }
+        }
+
         let body = serde_json::json!({
             "errors": [{
                 "code": code,
@@ -89,6 +103,12 @@ impl RegistryError {
                     .body(RegistryResponseBody::Fixed(Full::new(body)))
                     .unwrap()
             }
+            RegistryError::TooManyRequests(Some(retry_after)) => Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .header("Retry-After", retry_after.to_string())
+                .body(RegistryResponseBody::Fixed(Full::new(body)))
+                .unwrap(),
             _ => Response::builder()
                 .status(status)
                 .header("Content-Type", "application/json")
@@ -123,7 +143,7 @@ impl Display for RegistryError {
             RegistryError::BlobUploadInvalid => "blob upload invalid",
             RegistryError::BlobUploadUnknown => "blob upload unknown to registry",
             RegistryError::DigestInvalid => "provided digest did not match uploaded content",
-            RegistryError::ManifestBlobUnknown => {
+            RegistryError::ManifestBlobUnknown(_) => {
                 "manifest references a manifest or blob unknown to registry"
             }
             RegistryError::ManifestInvalid(Some(s)) => s.as_str(),
@@ -136,7 +156,7 @@ impl Display for RegistryError {
             RegistryError::Unauthorized(None) => "authentication required",
             RegistryError::Denied => "requested access to the resource is denied",
             RegistryError::Unsupported => "the operation is unsupported",
-            RegistryError::TooManyRequests => "too many requests",
+            RegistryError::TooManyRequests(_) => "too many requests",
             // Convenience
             RegistryError::RangeNotSatisfiable => "range not satisfiable",
             // Catch-all
@@ -1,3 +1,4 @@
+use crate::metrics::METRICS;
 use std::collections::HashMap;
 use std::sync::{Arc, Weak};
 use tokio::sync::{Mutex as AsyncMutex, RwLock};
@@ -16,12 +17,24 @@ impl MemoryLockManager {
 
     pub async fn read_lock(&self, key: String) -> InMemoryReadLockGuard {
         let lock = self.get_lock_for_key(&key).await;
-        lock.read_owned().await
+        match lock.clone().try_read_owned() {
+            Ok(guard) => guard,
+            Err(_) => {
+                METRICS.observe_lock_contention("memory", "read");
+                lock.read_owned().await
+            }
+        }
     }
 
     pub async fn write_lock(&self, key: String) -> InMemoryWriteLockGuard {
         let lock = self.get_lock_for_key(&key).await;
-        lock.write_owned().await
+        match lock.clone().try_write_owned() {
+            Ok(guard) => guard,
+            Err(_) => {
+                METRICS.observe_lock_contention("memory", "write");
+                lock.write_owned().await
+            }
+        }
     }
 
     async fn get_lock_for_key(&self, key: &str) -> Arc<RwLock<()>> {
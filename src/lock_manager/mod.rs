@@ -3,6 +3,8 @@ use crate::lock_manager::memory::{
     InMemoryReadLockGuard, InMemoryWriteLockGuard, MemoryLockManager,
 };
 use crate::lock_manager::redis::{RedisLockGuard, RedisLockManager};
+use crate::metrics::METRICS;
+use std::time::Instant;
 use tracing::{error, instrument};
 
 mod memory;
@@ -37,21 +39,42 @@ impl LockManager {
         Ok(LockManager::Redis(redis_lock))
     }
 
+    fn backend_name(&self) -> &'static str {
+        match self {
+            LockManager::Redis(_) => "redis",
+            LockManager::InMemory(_) => "memory",
+        }
+    }
+
     #[instrument(skip(self))]
-    pub async fn read_lock(&self, lock_key: String) -> Result<ReadGuard, RegistryError> {
+    pub async fn check_health(&self) -> Result<(), RegistryError> {
         match self {
+            LockManager::Redis(lock) => lock.check_health().await,
+            LockManager::InMemory(_) => Ok(()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn read_lock(&self, lock_key: String) -> Result<ReadGuard, RegistryError> {
+        let start = Instant::now();
+        let guard = match self {
             LockManager::Redis(lock) => lock.read_lock(lock_key).await.map(ReadGuard::Redis),
             LockManager::InMemory(lock) => Ok(ReadGuard::InMemory(lock.read_lock(lock_key).await)),
-        }
+        };
+        METRICS.observe_lock_acquire(self.backend_name(), "read", start.elapsed());
+        guard
     }
 
     #[instrument(skip(self))]
     pub async fn write_lock(&self, lock_key: String) -> Result<WriteGuard, RegistryError> {
-        match self {
+        let start = Instant::now();
+        let guard = match self {
             LockManager::Redis(lock) => lock.write_lock(lock_key).await.map(WriteGuard::Redis),
             LockManager::InMemory(lock) => {
                 Ok(WriteGuard::InMemory(lock.write_lock(lock_key).await))
             }
-        }
+        };
+        METRICS.observe_lock_acquire(self.backend_name(), "write", start.elapsed());
+        guard
     }
 }
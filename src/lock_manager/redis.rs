@@ -1,4 +1,5 @@
 use crate::error::RegistryError;
+use crate::metrics::METRICS;
 use redis::Client;
 use std::sync::Arc;
 use std::time::Duration;
@@ -54,6 +55,18 @@ impl RedisLockManager {
         Ok(RedisLockManager { client, ttl })
     }
 
+    pub async fn check_health(&self) -> Result<(), RegistryError> {
+        let ping = async {
+            let mut conn = self.client.get_multiplexed_async_connection().await?;
+            redis::cmd("PING").query_async::<String>(&mut conn).await
+        };
+
+        ping.await.map(|_| ()).map_err(|err| {
+            error!("Redis health check failed: {}", err);
+            RegistryError::InternalServerError(Some("Redis is unreachable".to_string()))
+        })
+    }
+
     pub async fn read_lock(&self, key: String) -> Result<RedisLockGuard, RegistryError> {
         let lock = self
             .acquire_lock_with_retry(key, false)
@@ -83,6 +96,10 @@ impl RedisLockManager {
     ) -> redis::RedisResult<Arc<RedisRwLockInner>> {
         let max_attempts = 10;
         for attempt in 0..max_attempts {
+            if attempt == 1 {
+                let mode = if is_writer { "write" } else { "read" };
+                METRICS.observe_lock_contention("redis", mode);
+            }
             if attempt > 0 {
                 tokio::time::sleep(Duration::from_secs(1)).await;
             }
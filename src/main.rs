@@ -1,5 +1,5 @@
 #![forbid(unsafe_code)]
-use crate::cmd::{Scrub, ScrubOptions, Server};
+use crate::cmd::{PolicyEval, PolicyEvalOptions, Scrub, ScrubOptions, Server};
 use clap::{ArgAction, Command};
 use cmd::CommandError;
 use notify::{recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
@@ -23,12 +23,14 @@ mod cmd;
 mod configuration;
 mod error;
 mod lock_manager;
+mod metrics;
 mod oci;
 mod policy;
 mod registry;
 mod storage;
 
 use crate::configuration::Configuration;
+use crate::metrics::METRICS;
 
 const DEFAULT_CONFIG_PATH: &str = "config.toml";
 
@@ -91,6 +93,38 @@ pub fn set_tracing(config: &Configuration) {
     subscriber.init();
 }
 
+async fn wait_for_shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+            }
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
 pub fn set_watcher_path(watcher: &mut RecommendedWatcher, path: &str) -> Result<(), CommandError> {
     watcher.watch(Path::new(path), RecursiveMode::Recursive)?;
 
@@ -161,6 +195,74 @@ async fn main() -> Result<(), CommandError> {
                         .help("Automatically fix any inconsistencies found"),
                 ),
         )
+        .subcommand(
+            Command::new("policy")
+                .about("Inspect the access policies")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("eval")
+                        .about("Evaluate the access policies for a simulated request")
+                        .arg(
+                            clap::Arg::new("config")
+                                .short('c')
+                                .long("config")
+                                .value_name("FILE")
+                                .help("Sets a custom configuration file"),
+                        )
+                        .arg(
+                            clap::Arg::new("tests")
+                                .short('t')
+                                .long("tests")
+                                .value_name("FILE")
+                                .conflicts_with("action")
+                                .help("Evaluates the test cases of a YAML file"),
+                        )
+                        .arg(
+                            clap::Arg::new("username")
+                                .short('u')
+                                .long("username")
+                                .help("Username of the identity"),
+                        )
+                        .arg(
+                            clap::Arg::new("identity-id")
+                                .short('i')
+                                .long("identity-id")
+                                .help("Identity id, resolved from the username if omitted"),
+                        )
+                        .arg(
+                            clap::Arg::new("cert-cn")
+                                .long("cert-cn")
+                                .action(ArgAction::Append)
+                                .help("Common name of the client certificate"),
+                        )
+                        .arg(
+                            clap::Arg::new("cert-o")
+                                .long("cert-o")
+                                .action(ArgAction::Append)
+                                .help("Organization of the client certificate"),
+                        )
+                        .arg(
+                            clap::Arg::new("client-ip")
+                                .long("client-ip")
+                                .help("IP address of the client"),
+                        )
+                        .arg(
+                            clap::Arg::new("header")
+                                .short('H')
+                                .long("header")
+                                .action(ArgAction::Append)
+                                .value_name("NAME: VALUE")
+                                .help("Request header"),
+                        )
+                        .arg(
+                            clap::Arg::new("action")
+                                .num_args(1..=2)
+                                .value_names(["ACTION", "TARGET"])
+                                .required_unless_present("tests")
+                                .help("Action and target, e.g. 'put-manifest team/app:1.0'"),
+                        ),
+                ),
+        )
         .subcommand(
             Command::new("serve")
                 .about("Start the registry server")
@@ -174,6 +276,9 @@ async fn main() -> Result<(), CommandError> {
         )
         .get_matches();
 
+    // The webhook HTTP client is built without a crypto provider, it uses the process default
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
     match matches.subcommand() {
         Some(("scrub", scrub_matches)) => {
             let config_path = get_config_path_from_matches(scrub_matches);
@@ -186,6 +291,20 @@ async fn main() -> Result<(), CommandError> {
             let scrub = Scrub::try_from_config(&config, &scrub_options)?;
             scrub.run().await
         }
+        Some(("policy", policy_matches)) => match policy_matches.subcommand() {
+            Some(("eval", eval_matches)) => {
+                let config_path = get_config_path_from_matches(eval_matches);
+                let config = Configuration::load(&config_path)?;
+
+                set_tracing(&config);
+
+                let eval_options = PolicyEvalOptions::from_matches(eval_matches)?;
+
+                let policy_eval = PolicyEval::try_from_config(&config)?;
+                policy_eval.run(&eval_options)
+            }
+            _ => unreachable!(),
+        },
         Some(("serve", run_matches)) => {
             let config_path = get_config_path_from_matches(run_matches);
             let config = Configuration::load(&config_path)?;
@@ -211,21 +330,35 @@ async fn main() -> Result<(), CommandError> {
                         Ok(config) => config,
                         Err(err) => {
                             error!("Failed to reload configuration: {}", err);
+                            METRICS.observe_config_reload(false);
                             return;
                         }
                     };
 
                     if let Err(err) = server.notify_config_change(&config) {
                         error!("Failed to notify server of configuration change: {}", err);
+                        METRICS.observe_config_reload(false);
                     } else {
                         info!("Server notified of configuration change");
+                        METRICS.observe_config_reload(true);
                     }
                 }
             })?;
             set_watcher_path(&mut config_watcher, config_path.as_str())?;
             set_tls_watcher_paths(&mut config_watcher, &config, tls_watched_paths)?;
 
-            server.run().await
+            let server_signal = server.clone();
+            tokio::spawn(async move {
+                wait_for_shutdown_signal().await;
+                server_signal.shutdown();
+            });
+
+            let res = server.run().await;
+
+            info!("Flushing pending traces");
+            global::shutdown_tracer_provider();
+
+            res
         }
         _ => unreachable!(),
     }
@@ -0,0 +1,222 @@
+use lazy_static::lazy_static;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::time::Duration;
+
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RequestLabels {
+    pub route: String,
+    pub method: String,
+    pub status: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StorageLabels {
+    pub backend: String,
+    pub operation: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct LockLabels {
+    pub backend: String,
+    pub mode: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ResultLabels {
+    pub result: String,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+fn latency_histogram() -> Histogram {
+    // From 1ms to ~32s
+    Histogram::new(exponential_buckets(0.001, 2.0, 16))
+}
+
+// Metrics are process-wide, so they survive configuration reloads
+pub struct Metrics {
+    registry: Registry,
+    http_requests: Family<RequestLabels, Counter>,
+    http_request_duration: HistogramFamily<RequestLabels>,
+    http_received_bytes: Counter,
+    http_sent_bytes: Counter,
+    active_uploads: Gauge,
+    storage_operation_duration: HistogramFamily<StorageLabels>,
+    lock_acquire_duration: HistogramFamily<LockLabels>,
+    lock_contentions: Family<LockLabels, Counter>,
+    config_reloads: Family<ResultLabels, Counter>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut registry = Registry::with_prefix("origin");
+
+        let http_requests = Family::<RequestLabels, Counter>::default();
+        registry.register(
+            "http_requests",
+            "Number of HTTP requests, by route, method and status",
+            http_requests.clone(),
+        );
+
+        let http_request_duration =
+            HistogramFamily::<RequestLabels>::new_with_constructor(latency_histogram);
+        registry.register(
+            "http_request_duration_seconds",
+            "Duration of HTTP requests until the response headers are sent, in seconds",
+            http_request_duration.clone(),
+        );
+
+        let http_received_bytes = Counter::default();
+        registry.register(
+            "http_received_bytes",
+            "Number of bytes received in request bodies",
+            http_received_bytes.clone(),
+        );
+
+        let http_sent_bytes = Counter::default();
+        registry.register(
+            "http_sent_bytes",
+            "Number of bytes sent in response bodies",
+            http_sent_bytes.clone(),
+        );
+
+        let active_uploads = Gauge::default();
+        registry.register(
+            "active_uploads",
+            "Number of blob uploads currently receiving data",
+            active_uploads.clone(),
+        );
+
+        let storage_operation_duration =
+            HistogramFamily::<StorageLabels>::new_with_constructor(latency_histogram);
+        registry.register(
+            "storage_operation_duration_seconds",
+            "Duration of storage engine operations, by backend and operation, in seconds",
+            storage_operation_duration.clone(),
+        );
+
+        let lock_acquire_duration =
+            HistogramFamily::<LockLabels>::new_with_constructor(latency_histogram);
+        registry.register(
+            "lock_acquire_duration_seconds",
+            "Time spent waiting to acquire locks, by backend and mode, in seconds",
+            lock_acquire_duration.clone(),
+        );
+
+        let lock_contentions = Family::<LockLabels, Counter>::default();
+        registry.register(
+            "lock_contentions",
+            "Number of lock acquisitions that had to wait for another holder",
+            lock_contentions.clone(),
+        );
+
+        let config_reloads = Family::<ResultLabels, Counter>::default();
+        registry.register(
+            "config_reloads",
+            "Number of configuration reloads, by result",
+            config_reloads.clone(),
+        );
+
+        Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            http_received_bytes,
+            http_sent_bytes,
+            active_uploads,
+            storage_operation_duration,
+            lock_acquire_duration,
+            lock_contentions,
+            config_reloads,
+        }
+    }
+
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut buffer = String::new();
+        encode(&mut buffer, &self.registry)?;
+        Ok(buffer)
+    }
+
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        let labels = RequestLabels {
+            route: route.to_string(),
+            method: method.to_string(),
+            status: status.to_string(),
+        };
+        self.http_requests.get_or_create(&labels).inc();
+        self.http_request_duration
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_received_bytes(&self, bytes: u64) {
+        self.http_received_bytes.inc_by(bytes);
+    }
+
+    pub fn observe_sent_bytes(&self, bytes: u64) {
+        self.http_sent_bytes.inc_by(bytes);
+    }
+
+    pub fn track_upload(&self) -> ActiveUploadGuard {
+        self.active_uploads.inc();
+        ActiveUploadGuard
+    }
+
+    pub fn observe_storage_operation(&self, backend: &str, operation: &str, duration: Duration) {
+        let labels = StorageLabels {
+            backend: backend.to_string(),
+            operation: operation.to_string(),
+        };
+        self.storage_operation_duration
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_lock_acquire(&self, backend: &str, mode: &str, duration: Duration) {
+        let labels = LockLabels {
+            backend: backend.to_string(),
+            mode: mode.to_string(),
+        };
+        self.lock_acquire_duration
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_lock_contention(&self, backend: &str, mode: &str) {
+        let labels = LockLabels {
+            backend: backend.to_string(),
+            mode: mode.to_string(),
+        };
+        self.lock_contentions.get_or_create(&labels).inc();
+    }
+
+    pub fn observe_config_reload(&self, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        let labels = ResultLabels {
+            result: result.to_string(),
+        };
+        self.config_reloads.get_or_create(&labels).inc();
+    }
+}
+
+// Decrements the active uploads gauge when dropped, even if the upload fails
+pub struct ActiveUploadGuard;
+
+impl Drop for ActiveUploadGuard {
+    fn drop(&mut self) {
+        METRICS.active_uploads.dec();
+    }
+}
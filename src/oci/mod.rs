@@ -7,6 +7,9 @@ mod reference;
 pub use digest::Digest;
 pub use reference::Reference;
 
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Manifest {
@@ -16,6 +19,8 @@ pub struct Manifest {
     #[serde(default)]
     pub layers: Vec<Descriptor>,
     #[serde(default)]
+    pub manifests: Vec<Descriptor>,
+    #[serde(default)]
     pub subject: Option<Descriptor>,
     #[serde(default)]
     pub annotations: HashMap<String, String>,
@@ -23,6 +28,17 @@ pub struct Manifest {
     pub artifact_type: Option<String>,
 }
 
+impl Manifest {
+    // The media type is optional in OCI manifests, in which case it is inferred from the content
+    pub fn effective_media_type(&self) -> String {
+        match &self.media_type {
+            Some(media_type) => media_type.clone(),
+            None if !self.manifests.is_empty() => OCI_IMAGE_INDEX_MEDIA_TYPE.to_string(),
+            None => OCI_IMAGE_MANIFEST_MEDIA_TYPE.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Descriptor {
@@ -35,6 +51,18 @@ pub struct Descriptor {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub artifact_type: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -49,7 +77,7 @@ impl Default for ReferrerList {
     fn default() -> Self {
         ReferrerList {
             schema_version: 2,
-            media_type: "application/vnd.oci.image.index.v1+json".to_string(),
+            media_type: OCI_IMAGE_INDEX_MEDIA_TYPE.to_string(),
             manifests: Vec::new(),
         }
     }
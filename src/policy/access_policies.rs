@@ -0,0 +1,115 @@
+use crate::configuration::Configuration;
+use crate::error::RegistryError;
+use crate::registry::RepositoryPattern;
+use cel_interpreter::Program;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use tracing::{debug, instrument};
+
+// Everything access checks depend on: the global and repository policies, and the identity
+// of each username. It is shared by the registry and the policy evaluation command.
+pub struct AccessPolicies {
+    pub identities: HashMap<String, String>,
+    pub global_policy_default_allow: bool,
+    pub global_policies: Vec<Program>,
+    pub repositories: Vec<RepositoryPattern>,
+    pub repository_default_allow: HashMap<String, bool>,
+    pub repository_inherit_policies: HashMap<String, bool>,
+    pub repository_policies: HashMap<String, Vec<Program>>,
+}
+
+impl Debug for AccessPolicies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessPolicies")
+            .field("identities", &self.identities.len())
+            .field(
+                "global_policy_default_allow",
+                &self.global_policy_default_allow,
+            )
+            .field("global_policies", &self.global_policies.len())
+            .field("repositories", &self.repositories.len())
+            .field(
+                "repository_default_allow",
+                &self.repository_default_allow.len(),
+            )
+            .field(
+                "repository_inherit_policies",
+                &self.repository_inherit_policies.len(),
+            )
+            .field("repository_policies", &self.repository_policies.len())
+            .finish()
+    }
+}
+
+impl AccessPolicies {
+    #[instrument(skip(config))]
+    pub fn try_from_config(config: &Configuration) -> Result<Self, RegistryError> {
+        let identities = config
+            .build_credentials()
+            .into_iter()
+            .map(|(username, (identity_id, _))| (username, identity_id))
+            .collect();
+
+        Ok(Self {
+            identities,
+            global_policy_default_allow: config.build_global_policy_default_allow(),
+            global_policies: config.build_global_policies()?,
+            repositories: config.build_repositories_list(),
+            repository_default_allow: config.build_repository_default_allow_list(),
+            repository_inherit_policies: config.build_repository_inherit_policies_list(),
+            repository_policies: config.build_repository_policies()?,
+        })
+    }
+
+    pub fn get_identity_id(&self, username: &str) -> Option<String> {
+        self.identities.get(username).cloned()
+    }
+
+    pub fn is_global_policy_default_allow(&self) -> bool {
+        self.global_policy_default_allow
+    }
+
+    pub fn get_global_policies(&self) -> &[Program] {
+        &self.global_policies
+    }
+
+    // Returns the repositories whose policies apply to the namespace, from the least specific
+    // parent to the closest repository. A repository that doesn't inherit policies ends the chain.
+    #[instrument]
+    pub fn get_repository_chain(&self, namespace: &str) -> Vec<String> {
+        let mut chain = Vec::new();
+        for repository in self.repositories.iter().filter(|r| r.matches(namespace)) {
+            let repository = repository.as_str();
+            chain.push(repository.to_string());
+
+            if !self.is_repository_inheriting_policies(repository) {
+                break;
+            }
+        }
+
+        chain.reverse();
+        debug!("Repository chain: {:?}", chain);
+        chain
+    }
+
+    #[instrument]
+    pub fn is_repository_inheriting_policies(&self, namespace: &str) -> bool {
+        *self
+            .repository_inherit_policies
+            .get(namespace)
+            .unwrap_or(&true)
+    }
+
+    #[instrument]
+    pub fn is_repository_policy_default_allow(&self, namespace: &str) -> bool {
+        *self
+            .repository_default_allow
+            .get(namespace)
+            .unwrap_or(&false)
+    }
+
+    #[instrument]
+    pub fn get_repository_policies(&self, namespace: &str) -> Option<&Vec<Program>> {
+        self.repository_policies.get(namespace)
+    }
+}
@@ -1,5 +1,29 @@
 use crate::policy::client_action::ClientAction;
+use cel_interpreter::objects::Key;
+use cel_interpreter::{to_value, Value};
+use chrono::{DateTime, Utc};
 use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Serde values have no timestamp type, so timestamps are inserted in the serialized maps
+fn insert_timestamp(value: &mut Value, key: &str, timestamp: Option<DateTime<Utc>>) {
+    if let Value::Map(map) = value {
+        let timestamp = timestamp
+            .map(|timestamp| Value::Timestamp(timestamp.fixed_offset()))
+            .unwrap_or(Value::Null);
+        Arc::make_mut(&mut map.map).insert(Key::String(Arc::new(key.to_string())), timestamp);
+    }
+}
+
+fn get_field_mut<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Map(map) => {
+            Arc::make_mut(&mut map.map).get_mut(&Key::String(Arc::new(key.to_string())))
+        }
+        _ => None,
+    }
+}
 
 #[derive(Debug, Serialize)]
 pub struct CELRequest {
@@ -7,32 +31,34 @@ pub struct CELRequest {
     pub namespace: Option<String>,
     pub digest: Option<String>,
     pub reference: Option<String>,
+    pub client_ip: Option<String>,
+    pub headers: HashMap<String, String>,
+    #[serde(skip)]
+    pub time: DateTime<Utc>,
 }
 
 impl CELRequest {
     pub fn new(
-        action: String,
-        namespace: Option<String>,
-        digest: Option<String>,
-        reference: Option<String>,
+        action: &ClientAction,
+        client_ip: Option<String>,
+        headers: HashMap<String, String>,
+        time: DateTime<Utc>,
     ) -> Self {
         CELRequest {
-            action,
-            namespace,
-            digest,
-            reference,
+            action: action.get_action_name(),
+            namespace: action.get_namespace(),
+            digest: action.get_digest().map(|d| d.to_string()),
+            reference: action.get_reference().map(|r| r.to_string()),
+            client_ip,
+            headers,
+            time,
         }
     }
-}
-
-impl From<ClientAction> for CELRequest {
-    fn from(action: ClientAction) -> Self {
-        let action_name = action.get_action_name();
-        let namespace = action.get_namespace();
-        let digest = action.get_digest().map(|d| d.to_string());
-        let reference = action.get_reference().map(|r| r.to_string());
 
-        CELRequest::new(action_name, namespace, digest, reference)
+    pub fn to_value(&self) -> Result<Value, String> {
+        let mut value = to_value(self).map_err(|e| e.to_string())?;
+        insert_timestamp(&mut value, "time", Some(self.time));
+        Ok(value)
     }
 }
 
@@ -55,12 +81,31 @@ impl CELIdentity {
             certificate,
         }
     }
+
+    pub fn to_value(&self) -> Result<Value, String> {
+        let mut value = to_value(self).map_err(|e| e.to_string())?;
+        if let Some(certificate) = get_field_mut(&mut value, "certificate") {
+            insert_timestamp(certificate, "not_before", self.certificate.not_before);
+            insert_timestamp(certificate, "not_after", self.certificate.not_after);
+        }
+        Ok(value)
+    }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
 pub struct CELIdentityCertificate {
     pub organizations: Vec<String>,
     pub common_names: Vec<String>,
+    pub dns_names: Vec<String>,
+    pub emails: Vec<String>,
+    pub ip_addresses: Vec<String>,
+    pub uris: Vec<String>,
+    pub issuer: Option<String>,
+    pub serial: Option<String>,
+    #[serde(skip)]
+    pub not_before: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub not_after: Option<DateTime<Utc>>,
 }
 
 impl CELIdentityCertificate {
@@ -68,6 +113,7 @@ impl CELIdentityCertificate {
         CELIdentityCertificate {
             organizations,
             common_names,
+            ..CELIdentityCertificate::default()
         }
     }
 }
@@ -0,0 +1,149 @@
+use crate::registry::glob_match;
+use cel_interpreter::extractors::{Arguments, This};
+use cel_interpreter::{Context, ExecutionError, FunctionContext, Value};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, Timelike};
+use chrono_tz::Tz;
+use ipnet::IpNet;
+use semver::{Version, VersionReq};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, ExecutionError>;
+
+// Registry specific functions, on top of the base CEL functions
+pub fn register(context: &mut Context) {
+    context.add_function("glob", glob);
+    context.add_function("cidr_contains", cidr_contains);
+    context.add_function("semver_matches", semver_matches);
+    context.add_function("getDayOfWeek", get_day_of_week);
+    context.add_function("getHours", get_hours);
+    context.add_function("getMinutes", get_minutes);
+    context.add_function("time_between", time_between);
+}
+
+// `glob(request.namespace, "team/*")`, false for null values
+fn glob(value: Value, pattern: Arc<String>) -> bool {
+    match value {
+        Value::String(value) => glob_match(&pattern, &value),
+        _ => false,
+    }
+}
+
+// `cidr_contains("10.0.0.0/8", request.client_ip)`, false for null or invalid addresses
+fn cidr_contains(ftx: &FunctionContext, cidr: Arc<String>, ip: Value) -> Result<bool> {
+    let network = cidr
+        .parse::<IpNet>()
+        .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| ftx.error(format!("invalid CIDR '{}'", cidr)))?;
+
+    Ok(match ip {
+        Value::String(ip) => ip.parse::<IpAddr>().is_ok_and(|ip| network.contains(&ip)),
+        _ => false,
+    })
+}
+
+// `semver_matches(request.reference, ">=1.0")`, false for null values and tags that are not
+// versions, such as `latest`. A leading `v` is ignored, e.g. `v1.2.3`.
+fn semver_matches(ftx: &FunctionContext, version: Value, requirement: Arc<String>) -> Result<bool> {
+    let requirement = VersionReq::parse(&requirement)
+        .map_err(|e| ftx.error(format!("invalid requirement '{}': {}", requirement, e)))?;
+
+    Ok(match version {
+        Value::String(version) => {
+            let version = version.strip_prefix('v').unwrap_or(&version);
+            Version::parse(version).is_ok_and(|version| requirement.matches(&version))
+        }
+        _ => false,
+    })
+}
+
+// Timestamp helpers take an optional IANA time zone, e.g. `request.time.getHours("Europe/Paris")`,
+// and default to UTC.
+fn parse_time_zone(ftx: &FunctionContext, time_zone: Option<&Value>) -> Result<Tz> {
+    match time_zone {
+        None => Ok(Tz::UTC),
+        Some(Value::String(name)) => name
+            .parse::<Tz>()
+            .map_err(|_| ftx.error(format!("invalid time zone '{}'", name))),
+        Some(_) => Err(ftx.error("time zone must be a string")),
+    }
+}
+
+fn local_time(
+    ftx: &FunctionContext,
+    timestamp: DateTime<FixedOffset>,
+    args: &[Value],
+    time_zone_index: usize,
+) -> Result<DateTime<Tz>> {
+    if args.len() > time_zone_index + 1 {
+        return Err(ExecutionError::InvalidArgumentCount {
+            expected: time_zone_index + 1,
+            actual: args.len(),
+        });
+    }
+
+    let time_zone = parse_time_zone(ftx, args.get(time_zone_index))?;
+    Ok(timestamp.with_timezone(&time_zone))
+}
+
+// Methods get the timestamp as target, the time zone is then the first argument
+fn method_time(
+    ftx: &FunctionContext,
+    timestamp: DateTime<FixedOffset>,
+    args: &[Value],
+) -> Result<DateTime<Tz>> {
+    let time_zone_index = if ftx.this.is_some() { 0 } else { 1 };
+    local_time(ftx, timestamp, args, time_zone_index)
+}
+
+// Day of the week, from 0 (Sunday) to 6 (Saturday), as in the CEL specification
+fn get_day_of_week(
+    ftx: &FunctionContext,
+    This(timestamp): This<DateTime<FixedOffset>>,
+    Arguments(args): Arguments,
+) -> Result<i64> {
+    let time = method_time(ftx, timestamp, &args)?;
+    Ok(time.weekday().num_days_from_sunday() as i64)
+}
+
+fn get_hours(
+    ftx: &FunctionContext,
+    This(timestamp): This<DateTime<FixedOffset>>,
+    Arguments(args): Arguments,
+) -> Result<i64> {
+    let time = method_time(ftx, timestamp, &args)?;
+    Ok(time.hour() as i64)
+}
+
+fn get_minutes(
+    ftx: &FunctionContext,
+    This(timestamp): This<DateTime<FixedOffset>>,
+    Arguments(args): Arguments,
+) -> Result<i64> {
+    let time = method_time(ftx, timestamp, &args)?;
+    Ok(time.minute() as i64)
+}
+
+// `time_between(request.time, "09:00", "18:00", "Europe/Paris")`: whether the local time of day
+// is in [start, end). Windows ending before they start span midnight, e.g. "22:00" to "06:00".
+fn time_between(ftx: &FunctionContext, Arguments(args): Arguments) -> Result<bool> {
+    let [Value::Timestamp(timestamp), Value::String(start), Value::String(end), ..] =
+        args.as_slice()
+    else {
+        return Err(ftx.error("expected a timestamp, a start time and an end time"));
+    };
+
+    let parse_time = |time: &str| {
+        NaiveTime::parse_from_str(time, "%H:%M")
+            .map_err(|_| ftx.error(format!("invalid time '{}', expected HH:MM", time)))
+    };
+    let start = parse_time(start)?;
+    let end = parse_time(end)?;
+    let time = local_time(ftx, *timestamp, &args, 3)?.time();
+
+    Ok(if start <= end {
+        start <= time && time < end
+    } else {
+        start <= time || time < end
+    })
+}
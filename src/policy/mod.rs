@@ -1,21 +1,122 @@
 use cel::{CELIdentity, CELIdentityCertificate, CELRequest};
 use cel_interpreter::{Context, Program, Value};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use tracing::{debug, error, info, instrument};
-use x509_parser::prelude::X509Certificate;
+use x509_parser::prelude::{GeneralName, X509Certificate};
 
+mod access_policies;
 mod cel;
 mod client_action;
+mod functions;
+mod token;
 
 use crate::error::RegistryError;
 use crate::registry::Registry;
+pub use access_policies::AccessPolicies;
 pub use client_action::ClientAction;
+pub use token::{TokenClaims, TokenIssuer};
 
 #[derive(Clone, Default)]
 pub struct ClientIdentity {
     pub cert_organizations: Vec<String>,
     pub cert_common_name: Vec<String>,
     pub credentials: Option<(String, String)>,
+    pub token: Option<TokenClaims>,
+    pub client_ip: Option<IpAddr>,
+    pub certificate: Option<CertificateDetails>,
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum PolicyResult {
+    Bool(bool),
+    Invalid(String),
+    Error(String),
+}
+
+// How a policy, or the default policy, evaluated while checking an access
+#[derive(Clone, Debug)]
+pub struct PolicyEvaluation {
+    pub repository: Option<String>,            // None for global policies
+    pub policy: Option<(usize, PolicyResult)>, // None when the default policy is applied
+    pub decision: Option<bool>,                // Some when this evaluation decided for its scope
+}
+
+// Evaluations are only recorded when explaining an access, not when serving requests
+#[derive(Debug, Default)]
+struct PolicyTrace(Option<Vec<PolicyEvaluation>>);
+
+impl PolicyTrace {
+    fn record(
+        &mut self,
+        repository: Option<&str>,
+        policy: Option<(usize, PolicyResult)>,
+        decision: Option<bool>,
+    ) {
+        if let Some(evaluations) = &mut self.0 {
+            evaluations.push(PolicyEvaluation {
+                repository: repository.map(str::to_string),
+                policy,
+                decision,
+            });
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CertificateDetails {
+    pub dns_names: Vec<String>,
+    pub emails: Vec<String>,
+    pub ip_addresses: Vec<String>,
+    pub uris: Vec<String>,
+    pub issuer: String,
+    pub serial: String,
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+}
+
+impl CertificateDetails {
+    pub fn from_cert(cert: &X509Certificate) -> Self {
+        let mut details = CertificateDetails {
+            dns_names: Vec::new(),
+            emails: Vec::new(),
+            ip_addresses: Vec::new(),
+            uris: Vec::new(),
+            issuer: cert.issuer().to_string(),
+            serial: hex::encode(cert.raw_serial()),
+            not_before: DateTime::from_timestamp(cert.validity().not_before.timestamp(), 0)
+                .unwrap_or_default(),
+            not_after: DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)
+                .unwrap_or_default(),
+        };
+
+        let Ok(Some(san)) = cert.subject_alternative_name() else {
+            return details;
+        };
+
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(name) => details.dns_names.push(name.to_string()),
+                GeneralName::RFC822Name(email) => details.emails.push(email.to_string()),
+                GeneralName::URI(uri) => details.uris.push(uri.to_string()),
+                GeneralName::IPAddress(ip) => {
+                    let ip = match ip.len() {
+                        4 => <[u8; 4]>::try_from(*ip).map(|ip| Ipv4Addr::from(ip).to_string()),
+                        _ => <[u8; 16]>::try_from(*ip).map(|ip| Ipv6Addr::from(ip).to_string()),
+                    };
+                    if let Ok(ip) = ip {
+                        details.ip_addresses.push(ip);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        details
+    }
 }
 
 impl Debug for ClientIdentity {
@@ -28,6 +129,10 @@ impl Debug for ClientIdentity {
             .field("cert_organizations", &self.cert_organizations.len())
             .field("cert_common_name", &self.cert_common_name.len())
             .field("credentials", &credentials)
+            .field("token", &self.token.as_ref().map(|token| token.jti.clone()))
+            .field("client_ip", &self.client_ip)
+            .field("certificate", &self.certificate.as_ref().map(|c| &c.serial))
+            .field("headers", &self.headers.keys())
             .finish()
     }
 }
@@ -63,6 +168,10 @@ impl ClientIdentity {
             cert_organizations,
             cert_common_name,
             credentials: None,
+            token: None,
+            client_ip: None,
+            certificate: Some(CertificateDetails::from_cert(cert)),
+            headers: HashMap::new(),
         })
     }
 
@@ -70,44 +179,213 @@ impl ClientIdentity {
         self.credentials = Some((username, password));
     }
 
+    pub fn set_token(&mut self, token: TokenClaims) {
+        self.token = Some(token);
+    }
+
+    pub fn set_client_ip(&mut self, client_ip: IpAddr) {
+        self.client_ip = Some(client_ip);
+    }
+
+    pub fn set_headers(&mut self, headers: HashMap<String, String>) {
+        self.headers = headers;
+    }
+
+    pub fn username(&self) -> Option<String> {
+        match (&self.credentials, &self.token) {
+            (Some((username, _)), _) => Some(username.clone()),
+            (None, Some(token)) => token.username(),
+            (None, None) => None,
+        }
+    }
+
+    // The username, only if the credentials are valid
+    fn authenticated_username(&self, registry: &Registry) -> Option<String> {
+        match (&self.credentials, &self.token) {
+            (Some((username, _)), _) => registry
+                .validate_credentials(&self.credentials)
+                .ok()
+                .map(|_| username.clone()),
+            (None, Some(token)) => token.username(),
+            (None, None) => None,
+        }
+    }
+
+    pub fn identity_id(&self, registry: &Registry) -> Option<String> {
+        self.authenticated_username(registry)
+            .and_then(|username| registry.get_identity_id(&username))
+    }
+
+    // Identifies the client for rate limiting: by username if authenticated,
+    // by certificate common name, or by IP address for anonymous clients.
+    pub fn rate_limit_key(&self, registry: &Registry) -> String {
+        if let Some(username) = self.authenticated_username(registry) {
+            format!("user:{}", username)
+        } else if let Some(common_name) = self.cert_common_name.first() {
+            format!("cert:{}", common_name)
+        } else if let Some(client_ip) = self.client_ip {
+            format!("ip:{}", client_ip)
+        } else {
+            "anonymous".to_string()
+        }
+    }
+
+    #[instrument(skip(registry))]
+    fn resolve_identity_id(
+        &self,
+        registry: &Registry,
+        action: &ClientAction,
+    ) -> Result<Option<String>, RegistryError> {
+        let Some(token) = &self.token else {
+            return registry.validate_credentials(&self.credentials);
+        };
+
+        if !token.allows(action) {
+            debug!("Token scope does not allow action: {:?}", action);
+            return Err(RegistryError::Unauthorized(Some(
+                "Insufficient token scope".to_string(),
+            )));
+        }
+
+        Ok(token
+            .username()
+            .and_then(|username| registry.get_identity_id(&username)))
+    }
+
     #[instrument(skip(registry))]
     pub fn can_do(&self, registry: &Registry, action: ClientAction) -> Result<(), RegistryError> {
-        let identity_id = registry.validate_credentials(&self.credentials)?;
+        let identity_id = self
+            .resolve_identity_id(registry, &action)
+            .inspect_err(|e| registry.audit_denied(self, None, &action, e))?;
 
-        let Some(namespace) = action.get_namespace() else {
-            return Ok(());
-        };
+        self.check_access(
+            &registry.access_policies,
+            action.clone(),
+            identity_id.clone(),
+            &mut PolicyTrace::default(),
+        )
+        .inspect_err(|e| registry.audit_denied(self, identity_id, &action, e))
+    }
 
-        let repository = registry
-            .get_repository(&namespace)
-            .ok_or_else(|| RegistryError::Unauthorized(Some("Repository not found".to_string())))?;
+    // Same as `can_do`, without recording denials in the audit log
+    #[instrument(skip(registry))]
+    pub fn is_allowed(&self, registry: &Registry, action: ClientAction) -> bool {
+        let Ok(identity_id) = self.resolve_identity_id(registry, &action) else {
+            return false;
+        };
 
-        let default_allow = registry.is_repository_policy_default_allow(&repository);
-        debug!(
-            "Default allow: {:?} for namespace: {:?} and repository: {:?}",
-            default_allow, namespace, repository
-        );
+        self.check_access(
+            &registry.access_policies,
+            action,
+            identity_id,
+            &mut PolicyTrace::default(),
+        )
+        .is_ok()
+    }
 
-        let policies = registry.get_repository_policies(&repository);
+    // Checks the access of a simulated identity, returning how each evaluated policy contributed
+    // to the decision. The identity id is taken as is, credentials and tokens are not verified.
+    pub fn explain_access(
+        &self,
+        access_policies: &AccessPolicies,
+        action: ClientAction,
+        identity_id: Option<String>,
+    ) -> (Result<(), RegistryError>, Vec<PolicyEvaluation>) {
+        let mut trace = PolicyTrace(Some(Vec::new()));
+        let result = self.check_access(access_policies, action, identity_id, &mut trace);
+        (result, trace.0.unwrap_or_default())
+    }
 
-        if let Some(policies) = policies {
-            self.check_policies(action, identity_id, policies, default_allow)
+    fn check_access(
+        &self,
+        access_policies: &AccessPolicies,
+        action: ClientAction,
+        identity_id: Option<String>,
+        trace: &mut PolicyTrace,
+    ) -> Result<(), RegistryError> {
+        // Global policies come first and apply to every action, even without a namespace,
+        // so a single rule can deny an identity everywhere
+        let global_default_allow = access_policies.is_global_policy_default_allow();
+        let global_policies = access_policies.get_global_policies();
+        if global_policies.is_empty() {
+            self.apply_default_policy(
+                action.clone(),
+                identity_id.clone(),
+                global_default_allow,
+                None,
+                trace,
+            )?;
         } else {
+            debug!("Checking global policies for action '{:?}'", action);
+            self.check_policies(
+                action.clone(),
+                identity_id.clone(),
+                global_policies,
+                global_default_allow,
+                None,
+                trace,
+            )?;
+        }
+
+        let Some(namespace) = action.get_namespace() else {
+            return Ok(());
+        };
+
+        let repositories = access_policies.get_repository_chain(&namespace);
+        if repositories.is_empty() {
+            return Err(RegistryError::Unauthorized(Some(
+                "Repository not found".to_string(),
+            )));
+        }
+
+        // Parent repositories are checked first, each of them must allow the action
+        for repository in repositories {
+            let default_allow = access_policies.is_repository_policy_default_allow(&repository);
             debug!(
-                "Applying default policy to repository '{:?}' and action '{:?}'",
-                repository, action
+                "Default allow: {:?} for namespace: {:?} and repository: {:?}",
+                default_allow, namespace, repository
             );
-            self.apply_default_policy(action, identity_id, default_allow)
+
+            let policies = access_policies.get_repository_policies(&repository);
+
+            if let Some(policies) = policies {
+                self.check_policies(
+                    action.clone(),
+                    identity_id.clone(),
+                    policies,
+                    default_allow,
+                    Some(&repository),
+                    trace,
+                )?;
+            } else {
+                debug!(
+                    "Applying default policy to repository '{:?}' and action '{:?}'",
+                    repository, action
+                );
+                self.apply_default_policy(
+                    action.clone(),
+                    identity_id.clone(),
+                    default_allow,
+                    Some(&repository),
+                    trace,
+                )?;
+            }
         }
+
+        Ok(())
     }
 
-    #[instrument]
+    #[instrument(skip(trace))]
     fn apply_default_policy(
         &self,
         action: ClientAction,
         identity_id: Option<String>,
         default_allow: bool,
+        repository: Option<&str>,
+        trace: &mut PolicyTrace,
     ) -> Result<(), RegistryError> {
+        trace.record(repository, None, Some(default_allow));
+
         if default_allow {
             Ok(())
         } else {
@@ -126,49 +404,72 @@ impl ClientIdentity {
         &self,
         identity_id: &Option<String>,
         action: &ClientAction,
-    ) -> Result<Context, RegistryError> {
-        let request = CELRequest::from(action.clone());
+    ) -> Result<Context<'_>, RegistryError> {
+        let request = CELRequest::new(
+            action,
+            self.client_ip.map(|ip| ip.to_string()),
+            self.headers.clone(),
+            Utc::now(),
+        );
         debug!("Policy context (request) : {:?}", request);
 
-        let username = self
-            .credentials
-            .as_ref()
-            .map(|(username, _)| username.clone());
-        let certificate = CELIdentityCertificate::new(
+        let username = self.username();
+        let mut certificate = CELIdentityCertificate::new(
             self.cert_organizations.clone(),
             self.cert_common_name.clone(),
         );
+        if let Some(details) = &self.certificate {
+            certificate.dns_names = details.dns_names.clone();
+            certificate.emails = details.emails.clone();
+            certificate.ip_addresses = details.ip_addresses.clone();
+            certificate.uris = details.uris.clone();
+            certificate.issuer = Some(details.issuer.clone());
+            certificate.serial = Some(details.serial.clone());
+            certificate.not_before = Some(details.not_before);
+            certificate.not_after = Some(details.not_after);
+        }
         let identity = CELIdentity::new(identity_id.clone(), username, certificate);
         debug!("Policy context (identity) : {:?}", identity);
 
-        let mut context = Context::default();
-        context.add_variable("request", &request).map_err(|e| {
+        let request = request.to_value().map_err(|e| {
             error!("Failed to add request to policy context: {}", e);
             RegistryError::Unauthorized(Some("Failed to add request to policy context".to_string()))
         })?;
-        context.add_variable("identity", &identity).map_err(|e| {
+        let identity = identity.to_value().map_err(|e| {
             error!("Failed to add identity to policy context: {}", e);
             RegistryError::Unauthorized(Some(
                 "Failed to add identity to policy context".to_string(),
             ))
         })?;
 
+        let mut context = Context::default();
+        functions::register(&mut context);
+        context.add_variable_from_value("request", request);
+        context.add_variable_from_value("identity", identity);
+
         Ok(context)
     }
 
-    #[instrument(skip(policies))]
+    #[instrument(skip(policies, trace))]
     fn check_policies(
         &self,
         action: ClientAction,
         identity_id: Option<String>,
         policies: &[Program],
         default_allow: bool,
+        repository: Option<&str>,
+        trace: &mut PolicyTrace,
     ) -> Result<(), RegistryError> {
         let context = self.build_policy_context(&identity_id, &action)?;
 
-        for policy in policies {
+        for (index, policy) in policies.iter().enumerate() {
             let evaluation_result = policy.execute(&context).map_err(|e| {
                 error!("Policy execution failed: {}", e);
+                trace.record(
+                    repository,
+                    Some((index, PolicyResult::Error(e.to_string()))),
+                    Some(false),
+                );
                 RegistryError::Unauthorized(Some("Policy execution failed".to_string()))
             })?;
             debug!("CEL program content {:?}", policy);
@@ -177,17 +478,35 @@ impl ClientIdentity {
             match evaluation_result {
                 Value::Bool(true) if !default_allow => {
                     debug!("Policy matched, allowing access");
+                    trace.record(
+                        repository,
+                        Some((index, PolicyResult::Bool(true))),
+                        Some(true),
+                    );
                     return Ok(());
                 }
                 Value::Bool(false) if default_allow => {
                     info!("Policy matched, denying access");
+                    trace.record(
+                        repository,
+                        Some((index, PolicyResult::Bool(false))),
+                        Some(false),
+                    );
                     return Err(RegistryError::Unauthorized(Some(
                         "Access denied (by policy)".to_string(),
                     )));
                 }
-                Value::Bool(_) => {} // Not validated, continue checking
-                _ => {
+                Value::Bool(value) => {
+                    // Not validated, continue checking
+                    trace.record(repository, Some((index, PolicyResult::Bool(value))), None);
+                }
+                value => {
                     info!("Policy returned invalid value, denying access");
+                    trace.record(
+                        repository,
+                        Some((index, PolicyResult::Invalid(format!("{:?}", value)))),
+                        Some(false),
+                    );
                     return Err(RegistryError::Unauthorized(Some(
                         "Access denied (by policy)".to_string(),
                     )));
@@ -196,6 +515,6 @@ impl ClientIdentity {
         }
 
         debug!("No policy matched, applying default policy");
-        self.apply_default_policy(action, identity_id, default_allow)
+        self.apply_default_policy(action, identity_id, default_allow, repository, trace)
     }
 }
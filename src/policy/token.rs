@@ -0,0 +1,266 @@
+use crate::configuration::TokenConfig;
+use crate::error::RegistryError;
+use crate::policy::client_action::ClientAction;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use tracing::{debug, instrument, warn};
+use uuid::Uuid;
+
+const TOKEN_ALGORITHM: &str = "HS256";
+const REPOSITORY_ACTIONS: [&str; 4] = ["pull", "push", "delete", "*"];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenHeader {
+    alg: String,
+    typ: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenAccess {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+impl TokenAccess {
+    // Scopes are formatted as "<type>:<name>:<action>[,<action>...]"
+    // e.g. "repository:team/app:pull,push" or "registry:catalog:*"
+    pub fn parse_scope(scope: &str) -> Option<Self> {
+        let (resource_type, rest) = scope.split_once(':')?;
+        let (name, actions) = rest.rsplit_once(':')?;
+
+        if resource_type.is_empty() || name.is_empty() {
+            return None;
+        }
+
+        let actions = actions
+            .split(',')
+            .filter(|action| !action.is_empty())
+            .map(String::from)
+            .collect();
+
+        Some(TokenAccess {
+            resource_type: resource_type.to_string(),
+            name: name.to_string(),
+            actions,
+        })
+    }
+
+    fn grantable(mut self) -> Option<Self> {
+        match self.resource_type.as_str() {
+            "repository" => self
+                .actions
+                .retain(|action| REPOSITORY_ACTIONS.contains(&action.as_str())),
+            "registry" if self.name == "catalog" => self.actions.retain(|action| action == "*"),
+            _ => return None,
+        }
+
+        if self.actions.is_empty() {
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    fn allows(&self, resource_type: &str, name: &str, action: &str) -> bool {
+        self.resource_type == resource_type
+            && self.name == name
+            && self.actions.iter().any(|a| a == action || a == "*")
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: i64,
+    pub nbf: i64,
+    pub iat: i64,
+    pub jti: String,
+    #[serde(default)]
+    pub access: Vec<TokenAccess>,
+}
+
+impl TokenClaims {
+    pub fn username(&self) -> Option<String> {
+        if self.sub.is_empty() {
+            None
+        } else {
+            Some(self.sub.clone())
+        }
+    }
+
+    pub fn allows(&self, action: &ClientAction) -> bool {
+        let (resource_type, name, required_action) = match action {
+            ClientAction::GetApiVersion => return true,
+            ClientAction::ListCatalog => ("registry", "catalog".to_string(), "*"),
+            ClientAction::GetBlob(namespace, _)
+            | ClientAction::GetManifest(namespace, _)
+            | ClientAction::GetReferrers(namespace, _)
+            | ClientAction::ListTags(namespace) => ("repository", namespace.clone(), "pull"),
+            ClientAction::PutBlob(namespace) | ClientAction::PutManifest(namespace, _) => {
+                ("repository", namespace.clone(), "push")
+            }
+            ClientAction::DeleteBlob(namespace, _) | ClientAction::DeleteManifest(namespace, _) => {
+                ("repository", namespace.clone(), "delete")
+            }
+        };
+
+        self.access
+            .iter()
+            .any(|access| access.allows(resource_type, &name, required_action))
+    }
+}
+
+pub struct IssuedToken {
+    pub token: String,
+    pub expires_in: u64,
+    pub issued_at: String,
+}
+
+pub struct TokenIssuer {
+    pub realm: String,
+    pub service: String,
+    issuer: String,
+    ttl: u64,
+    key: hmac::Key,
+}
+
+impl Debug for TokenIssuer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenIssuer")
+            .field("realm", &self.realm)
+            .field("service", &self.service)
+            .field("issuer", &self.issuer)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl TokenIssuer {
+    pub fn new(config: &TokenConfig) -> Self {
+        TokenIssuer {
+            realm: config.realm.clone(),
+            service: config.service.clone(),
+            issuer: config.issuer.clone(),
+            ttl: config.ttl,
+            key: hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()),
+        }
+    }
+
+    pub fn challenge(&self, scope: Option<String>) -> String {
+        match scope {
+            Some(scope) => format!(
+                "Bearer realm=\"{}\",service=\"{}\",scope=\"{}\"",
+                self.realm, self.service, scope
+            ),
+            None => format!(
+                "Bearer realm=\"{}\",service=\"{}\"",
+                self.realm, self.service
+            ),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub fn issue(
+        &self,
+        username: Option<String>,
+        service: Option<String>,
+        scopes: Vec<String>,
+    ) -> Result<IssuedToken, RegistryError> {
+        if let Some(service) = service {
+            if service != self.service {
+                warn!("Token requested for unknown service: {}", service);
+                return Err(RegistryError::Denied);
+            }
+        }
+
+        let access = scopes
+            .iter()
+            .filter_map(|scope| TokenAccess::parse_scope(scope))
+            .filter_map(TokenAccess::grantable)
+            .collect::<Vec<_>>();
+        debug!("Granting access: {:?}", access);
+
+        let now = Utc::now();
+        let claims = TokenClaims {
+            iss: self.issuer.clone(),
+            sub: username.unwrap_or_default(),
+            aud: self.service.clone(),
+            exp: now.timestamp() + self.ttl as i64,
+            nbf: now.timestamp(),
+            iat: now.timestamp(),
+            jti: Uuid::new_v4().to_string(),
+            access,
+        };
+
+        let header = TokenHeader {
+            alg: TOKEN_ALGORITHM.to_string(),
+            typ: "JWT".to_string(),
+        };
+
+        let header = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?);
+        let claims = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?);
+        let payload = format!("{}.{}", header, claims);
+
+        let signature = hmac::sign(&self.key, payload.as_bytes());
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(signature.as_ref());
+
+        Ok(IssuedToken {
+            token: format!("{}.{}", payload, signature),
+            expires_in: self.ttl,
+            issued_at: now.to_rfc3339(),
+        })
+    }
+
+    #[instrument(skip(self, token))]
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, RegistryError> {
+        let invalid_token = || RegistryError::Unauthorized(Some("Invalid token".to_string()));
+
+        let (payload, signature) = token.rsplit_once('.').ok_or_else(invalid_token)?;
+        let (header, claims) = payload.split_once('.').ok_or_else(invalid_token)?;
+
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| invalid_token())?;
+        hmac::verify(&self.key, payload.as_bytes(), &signature).map_err(|_| {
+            debug!("Token signature verification failed");
+            invalid_token()
+        })?;
+
+        let header = BASE64_URL_SAFE_NO_PAD
+            .decode(header)
+            .map_err(|_| invalid_token())?;
+        let header: TokenHeader = serde_json::from_slice(&header).map_err(|_| invalid_token())?;
+        if header.alg != TOKEN_ALGORITHM {
+            debug!("Unsupported token algorithm: {}", header.alg);
+            return Err(invalid_token());
+        }
+
+        let claims = BASE64_URL_SAFE_NO_PAD
+            .decode(claims)
+            .map_err(|_| invalid_token())?;
+        let claims: TokenClaims = serde_json::from_slice(&claims).map_err(|_| invalid_token())?;
+
+        let now = Utc::now().timestamp();
+        if claims.exp <= now || claims.nbf > now {
+            debug!("Token is expired or not yet valid");
+            return Err(RegistryError::Unauthorized(Some(
+                "Token is expired".to_string(),
+            )));
+        }
+
+        if claims.iss != self.issuer || claims.aud != self.service {
+            debug!("Token issuer or audience mismatch");
+            return Err(invalid_token());
+        }
+
+        Ok(claims)
+    }
+}
@@ -0,0 +1,190 @@
+use crate::configuration::AuditConfig;
+use crate::error::RegistryError;
+use crate::oci::{Digest, Reference};
+use crate::policy::{ClientAction, ClientIdentity};
+use chrono::Utc;
+use serde::Serialize;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::sync::{Mutex, OnceLock};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::error;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditDecision {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub identity_id: Option<String>,
+    pub username: Option<String>,
+    pub cert_common_name: Vec<String>,
+    pub client_addr: Option<String>,
+    pub action: String,
+    pub namespace: Option<String>,
+    pub reference: Option<String>,
+    pub digest: Option<String>,
+    pub decision: AuditDecision,
+    pub outcome: AuditOutcome,
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        identity: &ClientIdentity,
+        identity_id: Option<String>,
+        action: &ClientAction,
+        digest: Option<&Digest>,
+        decision: AuditDecision,
+        error: Option<&RegistryError>,
+    ) -> Self {
+        let reference = action
+            .get_reference()
+            .map(|reference| reference.to_string())
+            .or_else(|| action.get_digest().map(|digest| digest.to_string()));
+
+        // The digest is resolved by the operation, or taken from the action when known upfront
+        let digest = digest.cloned().or_else(|| match action.get_reference() {
+            Some(Reference::Digest(digest)) => Some(digest),
+            _ => action.get_digest(),
+        });
+
+        AuditEntry {
+            timestamp: Utc::now().to_rfc3339(),
+            identity_id,
+            username: identity.username(),
+            cert_common_name: identity.cert_common_name.clone(),
+            client_addr: identity.client_ip.map(|ip| ip.to_string()),
+            action: action.get_action_name(),
+            namespace: action.get_namespace(),
+            reference,
+            digest: digest.map(|digest| digest.to_string()),
+            decision,
+            outcome: if error.is_some() {
+                AuditOutcome::Failure
+            } else {
+                AuditOutcome::Success
+            },
+            error: error.map(|e| e.to_string()),
+        }
+    }
+}
+
+type AuditSink = Box<dyn AsyncWrite + Unpin + Send>;
+
+// Writes audit entries as JSON lines, to a file or to the standard output.
+// Entries are written by a background task, so logging never blocks request handling.
+pub struct AuditLogger {
+    sink: Option<Mutex<Option<AuditSink>>>,
+    queue: OnceLock<mpsc::UnboundedSender<Vec<u8>>>,
+    include_denied: bool,
+}
+
+impl Debug for AuditLogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLogger")
+            .field("enabled", &self.sink.is_some())
+            .field("include_denied", &self.include_denied)
+            .finish()
+    }
+}
+
+impl AuditLogger {
+    pub fn new(config: Option<&AuditConfig>) -> Result<Self, RegistryError> {
+        let Some(config) = config else {
+            return Ok(AuditLogger {
+                sink: None,
+                queue: OnceLock::new(),
+                include_denied: false,
+            });
+        };
+
+        let sink: AuditSink = match &config.path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| {
+                        error!("Unable to open audit log '{}': {}", path, e);
+                        RegistryError::InternalServerError(Some(
+                            "Unable to open audit log".to_string(),
+                        ))
+                    })?;
+                Box::new(File::from_std(file))
+            }
+            None => Box::new(tokio::io::stdout()),
+        };
+
+        Ok(AuditLogger {
+            sink: Some(Mutex::new(Some(sink))),
+            queue: OnceLock::new(),
+            include_denied: config.include_denied,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    pub fn includes_denied(&self) -> bool {
+        self.sink.is_some() && self.include_denied
+    }
+
+    pub fn log(&self, entry: &AuditEntry) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Unable to serialize audit entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        // The writer task is started on the first entry, from the request handling runtime.
+        // It stops once the queue is closed and drained, i.e. when the configuration is reloaded.
+        let queue = self.queue.get_or_init(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let sink = sink.lock().unwrap_or_else(|e| e.into_inner()).take();
+            if let Some(sink) = sink {
+                tokio::spawn(write_entries(sink, receiver));
+            }
+            sender
+        });
+
+        // Entries are never dropped, an audit log is expected to be complete
+        if queue.send(line).is_err() {
+            error!("Unable to write audit entry: the audit log is closed");
+        }
+    }
+}
+
+// Entries are written one at a time, so concurrent entries are never interleaved
+async fn write_entries(mut sink: AuditSink, mut receiver: mpsc::UnboundedReceiver<Vec<u8>>) {
+    while let Some(line) = receiver.recv().await {
+        if let Err(e) = sink.write_all(&line).await {
+            error!("Unable to write audit entry: {}", e);
+            continue;
+        }
+        if let Err(e) = sink.flush().await {
+            error!("Unable to flush audit log: {}", e);
+        }
+    }
+}
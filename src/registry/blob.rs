@@ -1,9 +1,12 @@
 use crate::error::RegistryError;
 use crate::oci::Digest;
-use crate::registry::{LinkReference, Registry};
+use crate::registry::{validate_namespace, LinkReference, Registry};
 use crate::storage::StorageEngineReader;
 use tokio::io::AsyncRead;
-use tracing::{instrument, warn};
+use tracing::{debug, instrument, warn};
+
+// Maximum number of ranges served in a multipart response, the Range header is ignored beyond
+const MAX_RANGES: usize = 16;
 
 pub enum BlobData<R>
 where
@@ -12,6 +15,28 @@ where
     Empty,
     Reader(R, u64),
     RangedReader(R, (u64, u64), u64),
+    MultipartRangedReader(Vec<(R, (u64, u64))>, u64),
+}
+
+// A byte range from a "Range" request header (RFC 7233), resolved against the blob size
+#[derive(Clone, Debug, PartialEq)]
+pub enum ByteRange {
+    FromTo(u64, u64),
+    From(u64),
+    Suffix(u64),
+}
+
+impl ByteRange {
+    pub fn resolve(&self, size: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::FromTo(start, end) if start < size => Some((start, end.min(size - 1))),
+            ByteRange::From(start) if start < size => Some((start, size - 1)),
+            ByteRange::Suffix(length) if length > 0 && size > 0 => {
+                Some((size.saturating_sub(length), size - 1))
+            }
+            _ => None,
+        }
+    }
 }
 
 pub struct BlobSummary {
@@ -26,7 +51,8 @@ impl Registry {
         namespace: &str,
         digest: Digest,
     ) -> Result<BlobSummary, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
+        self.check_blob_access(namespace, &digest).await?;
 
         let size = self.storage.get_blob_size(&digest).await?;
 
@@ -38,37 +64,83 @@ impl Registry {
         &self,
         namespace: &str,
         digest: &Digest,
-        range: Option<(u64, u64)>,
+        ranges: Vec<ByteRange>,
     ) -> Result<BlobData<impl StorageEngineReader>, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
+        self.check_blob_access(namespace, digest).await?;
 
         let total_length = self.storage.get_blob_size(digest).await?;
 
-        let start = if let Some((start, _)) = range {
-            if start > total_length {
-                warn!("Range start does not match content length");
-                return Err(RegistryError::RangeNotSatisfiable);
-            }
-            Some(start)
+        if ranges.is_empty() || ranges.len() > MAX_RANGES {
+            let reader = match self.storage.build_blob_reader(digest, None).await {
+                Ok(reader) => reader,
+                Err(RegistryError::BlobUnknown) => return Ok(BlobData::Empty),
+                Err(err) => return Err(err),
+            };
+
+            return Ok(BlobData::Reader(reader, total_length));
+        }
+
+        let ranges = ranges
+            .iter()
+            .filter_map(|range| range.resolve(total_length))
+            .collect::<Vec<_>>();
+
+        if ranges.is_empty() {
+            warn!("No satisfiable range for blob of size {}", total_length);
+            return Err(RegistryError::RangeNotSatisfiable);
+        }
+
+        let mut readers = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            let reader = match self.storage.build_blob_reader(digest, Some(start)).await {
+                Ok(reader) => reader,
+                Err(RegistryError::BlobUnknown) => return Ok(BlobData::Empty),
+                Err(err) => return Err(err),
+            };
+            readers.push((reader, (start, end)));
+        }
+
+        if readers.len() == 1 {
+            let (reader, range) = readers.remove(0);
+            return Ok(BlobData::RangedReader(reader, range, total_length));
+        }
+
+        Ok(BlobData::MultipartRangedReader(readers, total_length))
+    }
+
+    #[instrument]
+    pub async fn is_blob_linked(
+        &self,
+        namespace: &str,
+        digest: &Digest,
+    ) -> Result<bool, RegistryError> {
+        match self.storage.read_blob_index(digest).await {
+            Ok(index) => Ok(index.namespace.contains_key(namespace)),
+            Err(RegistryError::NameUnknown) | Err(RegistryError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Blobs are only accessible from namespaces referencing them, unless the legacy
+    // global blob access is enabled.
+    #[instrument]
+    pub async fn check_blob_access(
+        &self,
+        namespace: &str,
+        digest: &Digest,
+    ) -> Result<(), RegistryError> {
+        if self.global_blob_access || self.is_blob_linked(namespace, digest).await? {
+            Ok(())
         } else {
-            None
-        };
-
-        let reader = match self.storage.build_blob_reader(digest, start).await {
-            Ok(reader) => reader,
-            Err(RegistryError::BlobUnknown) => return Ok(BlobData::Empty),
-            Err(err) => return Err(err),
-        };
-
-        match range {
-            Some((start, end)) => Ok(BlobData::RangedReader(reader, (start, end), total_length)),
-            None => Ok(BlobData::Reader(reader, total_length)),
+            debug!("Blob {} is not linked in namespace {}", digest, namespace);
+            Err(RegistryError::BlobUnknown)
         }
     }
 
     #[instrument]
     pub async fn delete_blob(&self, namespace: &str, digest: Digest) -> Result<(), RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
         // TODO: ensure that the blob is not used by any other layer or config!
         let link = LinkReference::Layer(digest.clone());
@@ -1,6 +1,6 @@
 use crate::error::RegistryError;
 use crate::oci::{Descriptor, Digest};
-use crate::registry::Registry;
+use crate::registry::{validate_namespace, Registry};
 use tracing::instrument;
 
 impl Registry {
@@ -11,7 +11,7 @@ impl Registry {
         digest: Digest,
         artifact_type: Option<String>,
     ) -> Result<Vec<Descriptor>, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
         match self
             .storage
@@ -49,7 +49,7 @@ impl Registry {
         n: Option<u32>,
         last: Option<String>,
     ) -> Result<(Vec<String>, Option<String>), RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
         let n = n.unwrap_or(100);
 
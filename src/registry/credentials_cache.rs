@@ -0,0 +1,101 @@
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+
+// Caches successful credential verifications, so Argon2 only runs once per TTL for a given
+// username and password. Entries are keyed by a HMAC of the credentials using a random key,
+// so no plaintext password is kept in memory.
+pub struct CredentialsCache {
+    key: Option<hmac::Key>,
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<Vec<u8>, (String, Instant)>>,
+}
+
+impl Debug for CredentialsCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let entries = self.entries.lock().map(|e| e.len()).unwrap_or_default();
+        f.debug_struct("CredentialsCache")
+            .field("ttl", &self.ttl)
+            .field("capacity", &self.capacity)
+            .field("entries", &entries)
+            .finish()
+    }
+}
+
+impl CredentialsCache {
+    pub fn new(ttl: u64, capacity: usize) -> Self {
+        let key = if ttl > 0 && capacity > 0 {
+            hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .map_err(|_| error!("Unable to generate credentials cache key, cache disabled"))
+                .ok()
+        } else {
+            None
+        };
+
+        CredentialsCache {
+            key,
+            ttl: Duration::from_secs(ttl),
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cache_key(&self, username: &str, password: &str) -> Option<Vec<u8>> {
+        let key = self.key.as_ref()?;
+
+        // Length-prefix the username so that ("ab", "c") and ("a", "bc") don't collide
+        let mut data = Vec::with_capacity(8 + username.len() + password.len());
+        data.extend_from_slice(&(username.len() as u64).to_be_bytes());
+        data.extend_from_slice(username.as_bytes());
+        data.extend_from_slice(password.as_bytes());
+
+        Some(hmac::sign(key, &data).as_ref().to_vec())
+    }
+
+    pub fn get(&self, username: &str, password: &str) -> Option<String> {
+        let cache_key = self.cache_key(username, password)?;
+        let mut entries = self.entries.lock().ok()?;
+
+        match entries.get(&cache_key) {
+            Some((identity_id, inserted_at)) if inserted_at.elapsed() < self.ttl => {
+                debug!("Credentials cache hit for user: {}", username);
+                Some(identity_id.clone())
+            }
+            Some(_) => {
+                entries.remove(&cache_key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, username: &str, password: &str, identity_id: String) {
+        let Some(cache_key) = self.cache_key(username, password) else {
+            return;
+        };
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+
+        if entries.len() >= self.capacity && !entries.contains_key(&cache_key) {
+            entries.retain(|_, (_, inserted_at)| inserted_at.elapsed() < self.ttl);
+        }
+
+        if entries.len() >= self.capacity && !entries.contains_key(&cache_key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (_, inserted_at))| *inserted_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(cache_key, (identity_id, Instant::now()));
+    }
+}
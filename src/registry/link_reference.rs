@@ -8,6 +8,7 @@ pub enum LinkReference {
     Layer(Digest),
     Config(Digest),
     Referrer(Digest, Digest),
+    Manifest(Digest, Digest),
 }
 
 impl From<Reference> for LinkReference {
@@ -1,9 +1,15 @@
+use sha2::{Digest as ShaDigestTrait, Sha256};
+use std::collections::HashMap;
 use tokio::io::AsyncReadExt;
 use tracing::{debug, error, instrument, warn};
 
 use crate::error::RegistryError;
 use crate::oci::{Digest, Manifest, Reference};
-use crate::registry::{LinkReference, Registry};
+use crate::policy::ClientIdentity;
+use crate::registry::{validate_namespace, EventAction, EventTarget, LinkReference, Registry};
+
+const DEFAULT_PLATFORM_OS: &str = "linux";
+const DEFAULT_PLATFORM_ARCHITECTURE: &str = "amd64";
 
 pub struct ManifestData {
     pub media_type: Option<String>,
@@ -26,6 +32,8 @@ pub struct ManifestDigests {
     pub subject: Option<Digest>,
     pub config: Option<Digest>,
     pub layers: Vec<Digest>,
+    pub layer_media_types: HashMap<Digest, String>,
+    pub manifests: Vec<Digest>,
 }
 
 pub fn parse_manifest_digests(
@@ -67,10 +75,38 @@ pub fn parse_manifest_digests(
         .map(|layer| Digest::try_from(layer.digest.as_str()))
         .collect::<Result<Vec<_>, _>>()?;
 
+    let layer_media_types = layers
+        .iter()
+        .cloned()
+        .zip(manifest.layers.iter().map(|layer| layer.media_type.clone()))
+        .collect();
+
+    let manifests = manifest
+        .manifests
+        .iter()
+        .map(|manifest| Digest::try_from(manifest.digest.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+
     Ok(ManifestDigests {
         subject,
         config,
         layers,
+        layer_media_types,
+        manifests,
+    })
+}
+
+fn is_media_type_accepted(media_type: &str, accepted_media_types: &[String]) -> bool {
+    if accepted_media_types.is_empty() {
+        return true;
+    }
+
+    accepted_media_types.iter().any(|accepted| {
+        accepted == "*/*"
+            || accepted == media_type
+            || accepted
+                .strip_suffix("/*")
+                .is_some_and(|accepted_type| media_type.split('/').next() == Some(accepted_type))
     })
 }
 
@@ -80,9 +116,11 @@ impl Registry {
         &self,
         namespace: &str,
         reference: Reference,
+        accepted_media_types: &[String],
     ) -> Result<ManifestSummary, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
+        let by_tag = matches!(reference, Reference::Tag(_));
         let link = reference.into();
         let digest = self.storage.read_link(namespace, &link).await?;
 
@@ -98,62 +136,175 @@ impl Registry {
         let mut manifest_content = Vec::new();
         reader.read_to_end(&mut manifest_content).await?;
 
-        let manifest = serde_json::from_slice::<Manifest>(&manifest_content)?;
-        let size = manifest_content.len();
-
-        let media_type = manifest.media_type.clone();
+        let manifest = self
+            .negotiate_manifest(
+                namespace,
+                digest,
+                manifest_content,
+                accepted_media_types,
+                by_tag,
+            )
+            .await?;
 
         Ok(ManifestSummary {
-            media_type,
-            digest,
-            size,
+            media_type: manifest.media_type,
+            digest: manifest.digest,
+            size: manifest.content.len(),
         })
     }
 
-    #[instrument]
+    #[instrument(skip(identity))]
     pub async fn get_manifest(
         &self,
         namespace: &str,
         reference: Reference,
+        accepted_media_types: &[String],
+        identity: &ClientIdentity,
     ) -> Result<ManifestData, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
-        let link = reference.into();
+        let link = reference.clone().into();
         let digest = self.storage.read_link(namespace, &link).await?;
 
         let content = self.storage.read_blob(&digest).await?;
 
+        let by_tag = matches!(reference, Reference::Tag(_));
+        let manifest = self
+            .negotiate_manifest(namespace, digest, content, accepted_media_types, by_tag)
+            .await?;
+
+        let target = EventTarget::manifest(
+            namespace,
+            &reference,
+            &manifest.digest,
+            manifest.media_type.clone(),
+            manifest.content.len(),
+        );
+        self.notify(EventAction::Pull, target, identity);
+
+        Ok(manifest)
+    }
+
+    // Serves the stored manifest if its media type is accepted by the client.
+    // Otherwise, for image indexes pulled by tag, falls back to the child manifest of the default
+    // platform (linux/amd64) if the client accepts it, like docker distribution does for clients
+    // without manifest list support. Manifests pulled by digest are never substituted, as the
+    // content would not match the requested digest.
+    #[instrument(skip(content))]
+    async fn negotiate_manifest(
+        &self,
+        namespace: &str,
+        digest: Digest,
+        content: Vec<u8>,
+        accepted_media_types: &[String],
+        allow_fallback: bool,
+    ) -> Result<ManifestData, RegistryError> {
         let manifest = serde_json::from_slice::<Manifest>(&content).map_err(|e| {
             debug!("Failed to deserialize manifest: {}", e);
             RegistryError::ManifestInvalid(Some("Failed to deserialize manifest".to_string()))
         })?;
 
-        let media_type = manifest.media_type.clone();
+        if is_media_type_accepted(&manifest.effective_media_type(), accepted_media_types) {
+            return Ok(ManifestData {
+                media_type: manifest.media_type,
+                digest,
+                content,
+            });
+        }
+
+        if !allow_fallback {
+            debug!(
+                "Manifest media type {:?} not accepted by client: {:?}",
+                manifest.media_type, accepted_media_types
+            );
+            return Err(RegistryError::ManifestUnknown);
+        }
+
+        let fallback = manifest.manifests.iter().find(|descriptor| {
+            descriptor.platform.as_ref().is_some_and(|platform| {
+                platform.os == DEFAULT_PLATFORM_OS
+                    && platform.architecture == DEFAULT_PLATFORM_ARCHITECTURE
+            }) && is_media_type_accepted(&descriptor.media_type, accepted_media_types)
+        });
+
+        let Some(fallback) = fallback else {
+            debug!(
+                "Manifest media type {:?} not accepted by client: {:?}",
+                manifest.media_type, accepted_media_types
+            );
+            return Err(RegistryError::ManifestUnknown);
+        };
+
+        debug!(
+            "Falling back to default platform manifest: {}",
+            fallback.digest
+        );
+        let fallback_digest = Digest::try_from(fallback.digest.as_str())?;
+        let link = LinkReference::Digest(fallback_digest);
+        let digest = self.storage.read_link(namespace, &link).await?;
+        let content = self.storage.read_blob(&digest).await?;
+
+        let manifest = serde_json::from_slice::<Manifest>(&content).map_err(|e| {
+            debug!("Failed to deserialize manifest: {}", e);
+            RegistryError::ManifestInvalid(Some("Failed to deserialize manifest".to_string()))
+        })?;
 
         Ok(ManifestData {
-            media_type,
+            media_type: manifest.media_type,
             digest,
             content,
         })
     }
 
-    #[instrument(skip(body))]
+    #[instrument(skip(body, identity))]
     pub async fn put_manifest(
         &self,
         namespace: &str,
         reference: Reference,
         content_type: String,
         body: &[u8],
+        identity: &ClientIdentity,
     ) -> Result<NewManifest, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
+
+        let manifest_digests = parse_manifest_digests(body, Some(content_type.clone()))?;
 
-        let manifest_digests = parse_manifest_digests(body, Some(content_type))?;
+        let missing_digests = self
+            .find_missing_manifest_blobs(namespace, &manifest_digests)
+            .await?;
+        if !missing_digests.is_empty() {
+            warn!(
+                "Manifest references unknown blobs or manifests: {:?}",
+                missing_digests
+            );
+            return Err(RegistryError::ManifestBlobUnknown(
+                missing_digests.iter().map(Digest::to_string).collect(),
+            ));
+        }
 
-        let digest = match reference {
+        let digest = match reference.clone() {
             Reference::Tag(tag) => {
-                let digest = self.storage.create_blob(body).await?;
+                let link = LinkReference::Tag(tag.clone());
+                // Immutable tags can be pushed again, but only with the same manifest. The tag
+                // stays locked until it is linked, so concurrent first pushes can't both succeed,
+                // and the check comes first so denied pushes don't store anything.
+                let _guard = if self.is_tag_immutable(namespace, &tag) {
+                    let guard = self.storage.lock_tag(namespace, &tag).await?;
+                    let digest = Digest::Sha256(hex::encode(Sha256::digest(body)));
+                    match self.storage.read_link(namespace, &link).await {
+                        Ok(current_digest) if current_digest != digest => {
+                            warn!("Refusing to overwrite immutable tag: {}:{}", namespace, tag);
+                            return Err(RegistryError::Denied);
+                        }
+                        Ok(_) | Err(RegistryError::NameUnknown) | Err(RegistryError::NotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                    Some(guard)
+                } else {
+                    None
+                };
 
-                let link = LinkReference::Tag(tag);
+                let digest = self.storage.create_blob(body).await?;
                 self.storage.create_link(namespace, &link, &digest).await?;
                 let link = LinkReference::Digest(digest.clone());
                 self.storage.create_link(namespace, &link, &digest).await?;
@@ -184,40 +335,173 @@ impl Registry {
             self.storage.create_link(namespace, &link, &digest).await?;
         }
 
-        if let Some(config_digest) = manifest_digests.config {
+        if let Some(config_digest) = &manifest_digests.config {
             let link = LinkReference::Config(config_digest.clone());
             self.storage
-                .create_link(namespace, &link, &config_digest)
+                .create_link(namespace, &link, config_digest)
                 .await?;
         }
 
-        for layer_digest in manifest_digests.layers {
+        for layer_digest in &manifest_digests.layers {
+            // Foreign layers are hosted elsewhere, there is nothing to link
+            if self.is_foreign_layer(&manifest_digests, layer_digest) {
+                debug!("Skipping link of foreign layer: {}", layer_digest);
+                continue;
+            }
+
             let link = LinkReference::Layer(layer_digest.clone());
             self.storage
-                .create_link(namespace, &link, &layer_digest)
+                .create_link(namespace, &link, layer_digest)
+                .await?;
+        }
+
+        for manifest_digest in manifest_digests.manifests {
+            let link = LinkReference::Manifest(digest.clone(), manifest_digest.clone());
+            self.storage
+                .create_link(namespace, &link, &manifest_digest)
                 .await?;
         }
 
+        let target = EventTarget::manifest(
+            namespace,
+            &reference,
+            &digest,
+            Some(content_type),
+            body.len(),
+        );
+        self.notify(EventAction::Push, target, identity);
+
         Ok(NewManifest {
             digest,
             subject: manifest_digests.subject,
         })
     }
 
+    // Foreign layers are identified by their media type, they are never uploaded by clients
+    fn is_foreign_layer(&self, manifest_digests: &ManifestDigests, layer_digest: &Digest) -> bool {
+        manifest_digests
+            .layer_media_types
+            .get(layer_digest)
+            .is_some_and(|media_type| self.foreign_layer_media_types.contains(media_type))
+    }
+
+    // Config and layers must be available to the namespace, except foreign layers (by media type)
+    // which are not uploaded by clients. Image indexes can only reference manifests already
+    // pushed to the namespace.
+    #[instrument(skip(manifest_digests))]
+    async fn find_missing_manifest_blobs(
+        &self,
+        namespace: &str,
+        manifest_digests: &ManifestDigests,
+    ) -> Result<Vec<Digest>, RegistryError> {
+        let mut missing_digests = Vec::new();
+
+        let layers = manifest_digests
+            .layers
+            .iter()
+            .filter(|layer_digest| !self.is_foreign_layer(manifest_digests, layer_digest));
+
+        for blob_digest in manifest_digests.config.iter().chain(layers) {
+            if missing_digests.contains(blob_digest) {
+                continue;
+            }
+
+            let is_available = match self.check_blob_access(namespace, blob_digest).await {
+                Ok(()) => match self.storage.get_blob_size(blob_digest).await {
+                    Ok(_) => true,
+                    Err(
+                        RegistryError::BlobUnknown
+                        | RegistryError::NameUnknown
+                        | RegistryError::NotFound,
+                    ) => false,
+                    Err(e) => return Err(e),
+                },
+                Err(RegistryError::BlobUnknown) => false,
+                Err(e) => return Err(e),
+            };
+
+            if !is_available {
+                missing_digests.push(blob_digest.clone());
+            }
+        }
+
+        for manifest_digest in &manifest_digests.manifests {
+            let link = LinkReference::Digest(manifest_digest.clone());
+            if self.storage.read_link(namespace, &link).await.is_err() {
+                missing_digests.push(manifest_digest.clone());
+            }
+        }
+
+        Ok(missing_digests)
+    }
+
+    // Deleting a manifest by digest also deletes its tags, which is denied for immutable ones
     #[instrument]
+    async fn check_immutable_tags(
+        &self,
+        namespace: &str,
+        digest: &Digest,
+    ) -> Result<(), RegistryError> {
+        let patterns = self.get_immutable_tag_patterns(namespace);
+        if patterns.is_empty() {
+            return Ok(());
+        }
+
+        let mut marker = None;
+        loop {
+            let (tags, next_marker) = self.storage.list_tags(namespace, 100, marker).await?;
+            for tag in tags {
+                if !patterns.iter().any(|pattern| pattern.is_match(&tag)) {
+                    continue;
+                }
+
+                let link = LinkReference::Tag(tag.clone());
+                if self.storage.read_link(namespace, &link).await? == *digest {
+                    warn!(
+                        "Refusing to delete manifest with immutable tag: {}:{}",
+                        namespace, tag
+                    );
+                    return Err(RegistryError::Denied);
+                }
+            }
+
+            if next_marker.is_none() {
+                break;
+            }
+
+            marker = next_marker;
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(identity))]
     pub async fn delete_manifest(
         &self,
         namespace: &str,
         reference: Reference,
-    ) -> Result<(), RegistryError> {
-        self.validate_namespace(namespace)?;
+        identity: &ClientIdentity,
+    ) -> Result<Option<Digest>, RegistryError> {
+        validate_namespace(namespace)?;
+
+        let target = EventTarget::reference(namespace, &reference);
 
-        match reference {
+        let deleted_digest = match reference {
             Reference::Tag(tag) => {
+                if self.is_tag_immutable(namespace, &tag) {
+                    warn!("Refusing to delete immutable tag: {}:{}", namespace, tag);
+                    return Err(RegistryError::Denied);
+                }
+
                 let link = LinkReference::Tag(tag);
+                // Deleting an unknown tag is not an error, it just has no digest to report
+                let digest = self.storage.read_link(namespace, &link).await.ok();
                 self.storage.delete_link(namespace, &link).await?;
+                digest
             }
             Reference::Digest(digest) => {
+                self.check_immutable_tags(namespace, &digest).await?;
+
                 let mut marker = None;
                 loop {
                     let (tags, next_marker) =
@@ -235,6 +519,11 @@ impl Registry {
                     let content = self.storage.read_blob(&digest).await?;
                     let manifest_digests = parse_manifest_digests(&content, None)?;
 
+                    for manifest_digest in manifest_digests.manifests {
+                        let link = LinkReference::Manifest(digest.clone(), manifest_digest);
+                        self.storage.delete_link(namespace, &link).await?;
+                    }
+
                     if let Some(subject_digest) = manifest_digests.subject {
                         let link = LinkReference::Referrer(subject_digest, digest);
                         self.storage.delete_link(namespace, &link).await?;
@@ -248,9 +537,13 @@ impl Registry {
 
                     marker = next_marker;
                 }
+
+                Some(digest)
             }
-        }
+        };
 
-        Ok(())
+        self.notify(EventAction::Delete, target, identity);
+
+        Ok(deleted_digest)
     }
 }
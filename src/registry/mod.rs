@@ -1,53 +1,110 @@
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use cel_interpreter::Program;
+use hyper::header::HeaderName;
+use hyper::HeaderMap;
+use ipnet::IpNet;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::Duration;
 use tracing::{debug, error, instrument, warn};
 
+mod audit;
 mod blob;
 mod content_discovery;
+mod credentials_cache;
 mod link_reference;
 mod manifest;
+mod notifier;
+mod rate_limiter;
+mod repository_pattern;
 mod response;
 mod upload;
 
-pub use blob::BlobData;
+pub use audit::AuditLogger;
+pub use blob::{BlobData, ByteRange};
 pub use link_reference::LinkReference;
 pub use manifest::parse_manifest_digests;
+pub use notifier::{EventAction, EventTarget, Notifier};
+pub use rate_limiter::{RateLimitClass, RateLimiter};
+pub use repository_pattern::{glob_match, RepositoryPattern};
 pub use response::RegistryResponseBody;
 pub use upload::NewUpload;
 
+use audit::{AuditDecision, AuditEntry};
+use credentials_cache::CredentialsCache;
+
 use crate::configuration::Configuration;
 use crate::error::RegistryError;
+use crate::oci::Digest;
+use crate::policy::{AccessPolicies, ClientAction, ClientIdentity, TokenClaims, TokenIssuer};
 use crate::storage::StorageEngine;
 
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn check_with_timeout(
+    check: impl Future<Output = Result<(), RegistryError>>,
+) -> Result<(), RegistryError> {
+    tokio::time::timeout(READINESS_CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| {
+            Err(RegistryError::InternalServerError(Some(
+                "Health check timed out".to_string(),
+            )))
+        })
+}
+
 lazy_static! {
     static ref NAMESPACE_RE: Regex =
         Regex::new(r"^[a-z0-9]+(?:[._-][a-z0-9]+)*(?:/[a-z0-9]+(?:[._-][a-z0-9]+)*)*$").unwrap();
 }
 
+#[instrument]
+pub fn validate_namespace(namespace: &str) -> Result<(), RegistryError> {
+    if NAMESPACE_RE.is_match(namespace) {
+        Ok(())
+    } else {
+        Err(RegistryError::NameInvalid)
+    }
+}
+
 pub struct Registry {
-    pub streaming_chunk_size: u64,
+    pub global_blob_access: bool,
+    pub foreign_layer_media_types: HashSet<String>,
     pub storage: Box<dyn StorageEngine>,
     pub credentials: HashMap<String, (String, String)>,
-    pub repositories: HashSet<String>,
-    pub repository_default_allow: HashMap<String, bool>,
-    pub repository_policies: HashMap<String, Vec<Program>>,
+    credentials_cache: CredentialsCache,
+    pub token_issuer: Option<TokenIssuer>,
+    pub expose_metrics: bool,
+    trusted_proxies: Vec<IpNet>,
+    policy_headers: Vec<HeaderName>,
+    notifier: Notifier,
+    audit_logger: AuditLogger,
+    pub access_policies: AccessPolicies,
+    pub repository_immutable_tags: HashMap<String, Vec<Regex>>,
 }
 
 impl Debug for Registry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Registry")
+            .field("global_blob_access", &self.global_blob_access)
+            .field("foreign_layer_media_types", &self.foreign_layer_media_types)
             .field("storage", &self.storage)
             .field("credentials", &self.credentials.len())
-            .field("repositories", &self.repositories.len())
+            .field("credentials_cache", &self.credentials_cache)
+            .field("token_issuer", &self.token_issuer)
+            .field("expose_metrics", &self.expose_metrics)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("policy_headers", &self.policy_headers)
+            .field("notifier", &self.notifier)
+            .field("audit_logger", &self.audit_logger)
+            .field("access_policies", &self.access_policies)
             .field(
-                "repository_default_allow",
-                &self.repository_default_allow.len(),
+                "repository_immutable_tags",
+                &self.repository_immutable_tags.len(),
             )
-            .field("repository_policies", &self.repository_policies.len())
             .finish()
     }
 }
@@ -56,26 +113,27 @@ impl Registry {
     #[instrument(skip(config))]
     pub fn try_from_config(config: &Configuration) -> Result<Self, RegistryError> {
         let res = Self {
-            streaming_chunk_size: config.server.streaming_chunk_size.as_bytes(),
+            global_blob_access: config.server.global_blob_access,
+            foreign_layer_media_types: config.build_foreign_layer_media_types(),
             storage: config.build_storage_engine()?,
             credentials: config.build_credentials(),
-            repositories: config.build_repositories_list(),
-            repository_default_allow: config.build_repository_default_allow_list(),
-            repository_policies: config.build_repository_policies()?,
+            credentials_cache: CredentialsCache::new(
+                config.server.credentials_cache_ttl,
+                config.server.credentials_cache_size,
+            ),
+            token_issuer: config.build_token_issuer(),
+            expose_metrics: config.build_expose_metrics(),
+            trusted_proxies: config.build_trusted_proxies(),
+            policy_headers: config.build_policy_headers(),
+            notifier: config.build_notifier()?,
+            audit_logger: config.build_audit_logger()?,
+            access_policies: AccessPolicies::try_from_config(config)?,
+            repository_immutable_tags: config.build_repository_immutable_tags()?,
         };
 
         Ok(res)
     }
 
-    #[instrument]
-    pub fn validate_namespace(&self, namespace: &str) -> Result<(), RegistryError> {
-        if NAMESPACE_RE.is_match(namespace) {
-            Ok(())
-        } else {
-            Err(RegistryError::NameInvalid)
-        }
-    }
-
     #[instrument(skip(credentials))]
     pub fn validate_credentials(
         &self,
@@ -85,6 +143,10 @@ impl Registry {
             return Ok(None);
         };
 
+        if let Some(identity_id) = self.credentials_cache.get(username, password) {
+            return Ok(Some(identity_id));
+        }
+
         let (identity_id, identity_password) = self
             .credentials
             .get(username)
@@ -102,32 +164,146 @@ impl Registry {
                 RegistryError::Unauthorized(Some("Invalid credentials".to_string()))
             })?;
 
+        self.credentials_cache
+            .insert(username, password, identity_id.clone());
+
         Ok(Some(identity_id.clone()))
     }
 
-    #[instrument]
-    pub fn get_repository(&self, namespace: &str) -> Option<String> {
-        debug!("Looking for repository matching namespace: {}", namespace);
-        let repository = self
-            .repositories
+    #[instrument(skip(token))]
+    pub fn validate_token(&self, token: &str) -> Result<TokenClaims, RegistryError> {
+        let Some(token_issuer) = &self.token_issuer else {
+            return Err(RegistryError::Unauthorized(Some(
+                "Token authentication is not enabled".to_string(),
+            )));
+        };
+
+        token_issuer.verify(token)
+    }
+
+    pub fn get_identity_id(&self, username: &str) -> Option<String> {
+        self.access_policies.get_identity_id(username)
+    }
+
+    // Follows the X-Forwarded-For hops from the closest one, as long as they come from trusted
+    // proxies: the first untrusted hop is the client.
+    pub fn resolve_client_ip(&self, peer_ip: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let is_trusted = |ip: &IpAddr| self.trusted_proxies.iter().any(|net| net.contains(ip));
+
+        let forwarded_for = headers
+            .get_all("X-Forwarded-For")
             .iter()
-            .find(|&n| namespace.starts_with(n))
-            .cloned();
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+
+        let mut client_ip = peer_ip;
+        for hop in forwarded_for.into_iter().rev() {
+            if !is_trusted(&client_ip) {
+                break;
+            }
 
-        debug!("Found repository: {:?}", repository);
-        repository
+            match hop.parse() {
+                Ok(ip) => client_ip = ip,
+                Err(_) => {
+                    debug!("Ignoring invalid X-Forwarded-For hop: {}", hop);
+                    break;
+                }
+            }
+        }
+
+        client_ip
+    }
+
+    // Request headers exposed to policies, keyed by lowercase name
+    pub fn select_policy_headers(&self, headers: &HeaderMap) -> HashMap<String, String> {
+        self.policy_headers
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(name)?.to_str().ok()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect()
     }
 
+    // Checks the dependencies of the registry, returning the error of each failing one
     #[instrument]
-    pub fn is_repository_policy_default_allow(&self, namespace: &str) -> bool {
-        *self
-            .repository_default_allow
-            .get(namespace)
-            .unwrap_or(&false)
+    pub async fn check_readiness(&self) -> Vec<(&'static str, Result<(), RegistryError>)> {
+        let (storage, lock) = tokio::join!(
+            check_with_timeout(self.storage.check_health()),
+            check_with_timeout(self.storage.check_lock_health()),
+        );
+
+        vec![("storage", storage), ("lock", lock)]
+    }
+
+    // Queues an event for the webhooks interested in it, without waiting for its delivery
+    pub fn notify(&self, action: EventAction, target: EventTarget, identity: &ClientIdentity) {
+        self.notifier.notify(action, target, identity);
+    }
+
+    // Records the outcome of an allowed action, along with the digest it resolved to
+    pub fn audit<T>(
+        &self,
+        identity: &ClientIdentity,
+        action: &ClientAction,
+        digest: Option<&Digest>,
+        result: &Result<T, RegistryError>,
+    ) {
+        if !self.audit_logger.is_enabled() {
+            return;
+        }
+
+        let identity_id = identity.identity_id(self);
+        let entry = AuditEntry::new(
+            identity,
+            identity_id,
+            action,
+            digest,
+            AuditDecision::Allow,
+            result.as_ref().err(),
+        );
+        self.audit_logger.log(&entry);
     }
 
+    // Records an action denied by authentication or by policies, if enabled
+    pub fn audit_denied(
+        &self,
+        identity: &ClientIdentity,
+        identity_id: Option<String>,
+        action: &ClientAction,
+        error: &RegistryError,
+    ) {
+        if !self.audit_logger.includes_denied() {
+            return;
+        }
+
+        let entry = AuditEntry::new(
+            identity,
+            identity_id,
+            action,
+            None,
+            AuditDecision::Deny,
+            Some(error),
+        );
+        self.audit_logger.log(&entry);
+    }
+
+    // Immutable tag rules apply along the repository chain, like policies
     #[instrument]
-    pub fn get_repository_policies(&self, namespace: &str) -> Option<&Vec<Program>> {
-        self.repository_policies.get(namespace)
+    pub fn get_immutable_tag_patterns(&self, namespace: &str) -> Vec<&Regex> {
+        self.access_policies
+            .get_repository_chain(namespace)
+            .iter()
+            .filter_map(|repository| self.repository_immutable_tags.get(repository))
+            .flatten()
+            .collect()
+    }
+
+    pub fn is_tag_immutable(&self, namespace: &str, tag: &str) -> bool {
+        self.get_immutable_tag_patterns(namespace)
+            .iter()
+            .any(|pattern| pattern.is_match(tag))
     }
 }
@@ -0,0 +1,317 @@
+use crate::configuration::WebhookConfig;
+use crate::error::RegistryError;
+use crate::oci::{Digest, Reference};
+use crate::policy::ClientIdentity;
+use chrono::Utc;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::fmt::Debug;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+pub const EVENTS_MEDIA_TYPE: &str = "application/vnd.docker.distribution.events.v1+json";
+const BLOB_MEDIA_TYPE: &str = "application/octet-stream";
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventAction {
+    Push,
+    Pull,
+    Delete,
+}
+
+impl EventAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "push" => Some(EventAction::Push),
+            "pull" => Some(EventAction::Pull),
+            "delete" => Some(EventAction::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EventTarget {
+    #[serde(rename = "mediaType", skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    pub repository: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+impl EventTarget {
+    pub fn blob(namespace: &str, digest: &Digest, size: u64) -> Self {
+        EventTarget {
+            media_type: Some(BLOB_MEDIA_TYPE.to_string()),
+            digest: Some(digest.to_string()),
+            size: Some(size),
+            length: Some(size),
+            repository: namespace.to_string(),
+            tag: None,
+        }
+    }
+
+    pub fn manifest(
+        namespace: &str,
+        reference: &Reference,
+        digest: &Digest,
+        media_type: Option<String>,
+        size: usize,
+    ) -> Self {
+        let tag = match reference {
+            Reference::Tag(tag) => Some(tag.clone()),
+            Reference::Digest(_) => None,
+        };
+
+        EventTarget {
+            media_type,
+            digest: Some(digest.to_string()),
+            size: Some(size as u64),
+            length: Some(size as u64),
+            repository: namespace.to_string(),
+            tag,
+        }
+    }
+
+    pub fn reference(namespace: &str, reference: &Reference) -> Self {
+        let (digest, tag) = match reference {
+            Reference::Tag(tag) => (None, Some(tag.clone())),
+            Reference::Digest(digest) => (Some(digest.to_string()), None),
+        };
+
+        EventTarget {
+            digest,
+            repository: namespace.to_string(),
+            tag,
+            ..EventTarget::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EventActor {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EventRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+}
+
+// Event format compatible with docker distribution notifications
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    pub id: String,
+    pub timestamp: String,
+    pub action: EventAction,
+    pub target: EventTarget,
+    pub request: EventRequest,
+    pub actor: EventActor,
+}
+
+impl Event {
+    pub fn new(action: EventAction, target: EventTarget, identity: &ClientIdentity) -> Self {
+        Event {
+            id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            action,
+            target,
+            request: EventRequest {
+                addr: identity.client_ip.map(|ip| ip.to_string()),
+            },
+            actor: EventActor {
+                name: identity.username(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    events: [&'a Event; 1],
+}
+
+struct WebhookEndpoint {
+    config: Arc<WebhookConfig>,
+    actions: Vec<EventAction>,
+    client: reqwest::Client,
+    queue: OnceLock<mpsc::Sender<Arc<Event>>>,
+}
+
+impl WebhookEndpoint {
+    fn matches(&self, action: EventAction, repository: &str) -> bool {
+        if !self.actions.contains(&action) {
+            return false;
+        }
+
+        self.config.repositories.is_empty()
+            || self.config.repositories.iter().any(|filter| {
+                repository == filter
+                    || repository
+                        .strip_prefix(filter.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    // The delivery task is started on the first event, from the request handling runtime.
+    // It stops once the queue is closed and drained, i.e. when the configuration is reloaded.
+    fn enqueue(&self, event: Arc<Event>) {
+        let queue = self.queue.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel(self.config.queue_size);
+            tokio::spawn(deliver_events(
+                self.config.clone(),
+                self.client.clone(),
+                receiver,
+            ));
+            sender
+        });
+
+        if queue.try_send(event).is_err() {
+            warn!(
+                "Webhook '{}' queue is full, dropping event",
+                self.config.name
+            );
+        }
+    }
+}
+
+async fn deliver_events(
+    config: Arc<WebhookConfig>,
+    client: reqwest::Client,
+    mut receiver: mpsc::Receiver<Arc<Event>>,
+) {
+    while let Some(event) = receiver.recv().await {
+        let mut retry_interval = Duration::from_secs(config.retry_interval);
+
+        for attempt in 0..=config.max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+            }
+
+            match send_event(&config, &client, &event).await {
+                Ok(()) => {
+                    debug!("Event {} delivered to webhook '{}'", event.id, config.name);
+                    break;
+                }
+                Err(e) if attempt == config.max_retries => {
+                    error!(
+                        "Unable to deliver event {} to webhook '{}', dropping it: {}",
+                        event.id, config.name, e
+                    );
+                }
+                Err(e) => {
+                    warn!(
+                        "Unable to deliver event {} to webhook '{}' (attempt {}): {}",
+                        event.id,
+                        config.name,
+                        attempt + 1,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+async fn send_event(
+    config: &WebhookConfig,
+    client: &reqwest::Client,
+    event: &Event,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(&Envelope { events: [event] }).map_err(|e| e.to_string())?;
+
+    let mut request = client
+        .post(&config.url)
+        .header(CONTENT_TYPE, EVENTS_MEDIA_TYPE)
+        .body(body);
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("unexpected status {}", response.status()));
+    }
+
+    Ok(())
+}
+
+// Sends registry events to the configured webhooks, through a bounded queue per webhook
+// so requests are never slowed down by deliveries.
+pub struct Notifier {
+    endpoints: Vec<WebhookEndpoint>,
+}
+
+impl Debug for Notifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let endpoints = self
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.config.name.as_str())
+            .collect::<Vec<_>>();
+        f.debug_struct("Notifier")
+            .field("endpoints", &endpoints)
+            .finish()
+    }
+}
+
+impl Notifier {
+    pub fn new(configs: &[WebhookConfig]) -> Result<Self, RegistryError> {
+        let mut endpoints = Vec::new();
+
+        for config in configs {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(config.timeout))
+                .build()
+                .map_err(|e| {
+                    error!("Unable to build webhook '{}' client: {}", config.name, e);
+                    RegistryError::InternalServerError(Some(
+                        "Unable to build webhook client".to_string(),
+                    ))
+                })?;
+
+            endpoints.push(WebhookEndpoint {
+                config: Arc::new(config.clone()),
+                actions: config
+                    .actions
+                    .iter()
+                    .filter_map(|action| EventAction::from_name(action))
+                    .collect(),
+                client,
+                queue: OnceLock::new(),
+            });
+        }
+
+        Ok(Notifier { endpoints })
+    }
+
+    pub fn notify(&self, action: EventAction, target: EventTarget, identity: &ClientIdentity) {
+        let endpoints = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.matches(action, &target.repository))
+            .collect::<Vec<_>>();
+        if endpoints.is_empty() {
+            return;
+        }
+
+        let event = Arc::new(Event::new(action, target, identity));
+        for endpoint in endpoints {
+            endpoint.enqueue(event.clone());
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::configuration::{RateLimitConfig, RateLimitRuleConfig, TokenBucketConfig};
+use crate::error::RegistryError;
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{debug, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimitClass {
+    Pull,
+    Push,
+    Catalog,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &TokenBucketConfig) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &TokenBucketConfig) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+
+    fn is_full(&self, limit: &TokenBucketConfig) -> bool {
+        let elapsed = self.updated_at.elapsed().as_secs_f64();
+        self.tokens + elapsed * limit.rate >= limit.burst as f64
+    }
+
+    // Takes a token from the bucket, or returns the number of seconds to wait for one
+    fn acquire(&mut self, limit: &TokenBucketConfig) -> Result<(), u64> {
+        self.refill(limit);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / limit.rate;
+            Err(wait.ceil().max(1.0) as u64)
+        }
+    }
+}
+
+// Token buckets of a class of action. Limits are read from the current configuration on each
+// check, so buckets survive configuration reloads.
+#[derive(Debug, Default)]
+struct RateLimitBuckets {
+    identity_buckets: Mutex<HashMap<String, TokenBucket>>,
+    global_bucket: Mutex<Option<TokenBucket>>,
+}
+
+impl RateLimitBuckets {
+    fn acquire_identity(
+        &self,
+        limit: &TokenBucketConfig,
+        identity: &str,
+        max_tracked_clients: usize,
+    ) -> Result<(), u64> {
+        let Ok(mut buckets) = self.identity_buckets.lock() else {
+            return Ok(());
+        };
+
+        if buckets.len() >= max_tracked_clients && !buckets.contains_key(identity) {
+            // A full bucket is equivalent to a missing one, it can be dropped safely
+            buckets.retain(|_, bucket| !bucket.is_full(limit));
+        }
+
+        if buckets.len() >= max_tracked_clients && !buckets.contains_key(identity) {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                buckets.remove(&oldest);
+            }
+        }
+
+        buckets
+            .entry(identity.to_string())
+            .or_insert_with(|| TokenBucket::new(limit))
+            .acquire(limit)
+    }
+
+    fn acquire_global(&self, limit: &TokenBucketConfig) -> Result<(), u64> {
+        let Ok(mut bucket) = self.global_bucket.lock() else {
+            return Ok(());
+        };
+
+        bucket
+            .get_or_insert_with(|| TokenBucket::new(limit))
+            .acquire(limit)
+    }
+
+    // Drops the buckets of limits that are no longer configured
+    fn retain(&self, rule: Option<&RateLimitRuleConfig>) {
+        if rule.and_then(|rule| rule.per_identity.as_ref()).is_none() {
+            if let Ok(mut buckets) = self.identity_buckets.lock() {
+                buckets.clear();
+            }
+        }
+        if rule.and_then(|rule| rule.global.as_ref()).is_none() {
+            if let Ok(mut bucket) = self.global_bucket.lock() {
+                *bucket = None;
+            }
+        }
+    }
+}
+
+// Token-bucket rate limiting, per identity and for all clients, by class of action.
+// The per-identity limit is checked first, so a client exceeding its own limit doesn't
+// consume the global allowance shared with everyone else.
+// The limiter outlives the registry: it is owned by the listener and only its limits are
+// updated when the configuration is reloaded.
+pub struct RateLimiter {
+    config: ArcSwap<RateLimitConfig>,
+    pull: RateLimitBuckets,
+    push: RateLimitBuckets,
+    catalog: RateLimitBuckets,
+}
+
+impl Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let config = self.config.load();
+        f.debug_struct("RateLimiter")
+            .field("pull", &config.pull.is_some())
+            .field("push", &config.push.is_some())
+            .field("catalog", &config.catalog.is_some())
+            .field("max_tracked_clients", &config.max_tracked_clients)
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            config: ArcSwap::new(Arc::new(config.clone())),
+            pull: RateLimitBuckets::default(),
+            push: RateLimitBuckets::default(),
+            catalog: RateLimitBuckets::default(),
+        }
+    }
+
+    // Applies new limits, keeping the state of the buckets that are still limited
+    pub fn update(&self, config: &RateLimitConfig) {
+        self.config.store(Arc::new(config.clone()));
+
+        self.pull.retain(config.pull.as_ref());
+        self.push.retain(config.push.as_ref());
+        self.catalog.retain(config.catalog.as_ref());
+    }
+
+    fn get_rule<'a>(
+        &'a self,
+        config: &'a RateLimitConfig,
+        class: RateLimitClass,
+    ) -> Option<(&'a RateLimitRuleConfig, &'a RateLimitBuckets)> {
+        match class {
+            RateLimitClass::Pull => config.pull.as_ref().map(|rule| (rule, &self.pull)),
+            RateLimitClass::Push => config.push.as_ref().map(|rule| (rule, &self.push)),
+            RateLimitClass::Catalog => config.catalog.as_ref().map(|rule| (rule, &self.catalog)),
+        }
+    }
+
+    pub fn is_enabled(&self, class: RateLimitClass) -> bool {
+        self.get_rule(&self.config.load(), class).is_some()
+    }
+
+    pub fn check(&self, class: RateLimitClass, identity: &str) -> Result<(), RegistryError> {
+        let config = self.config.load();
+        let Some((rule, buckets)) = self.get_rule(&config, class) else {
+            return Ok(());
+        };
+
+        if let Some(limit) = &rule.per_identity {
+            buckets
+                .acquire_identity(limit, identity, config.max_tracked_clients)
+                .map_err(|retry_after| {
+                    debug!(
+                        "Rate limit exceeded for {:?} by '{}', retry after {}s",
+                        class, identity, retry_after
+                    );
+                    RegistryError::TooManyRequests(Some(retry_after))
+                })?;
+        }
+
+        if let Some(limit) = &rule.global {
+            buckets.acquire_global(limit).map_err(|retry_after| {
+                warn!(
+                    "Global rate limit exceeded for {:?}, retry after {}s",
+                    class, retry_after
+                );
+                RegistryError::TooManyRequests(Some(retry_after))
+            })?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::cmp::Ordering;
+
+lazy_static! {
+    // Same constraints as namespace components, with `*` and `?` wildcards, or `**` alone.
+    static ref SEGMENT_RE: Regex =
+        Regex::new(r"^(?:\*\*|[a-z0-9*?]+(?:[._-][a-z0-9*?]+)*)$").unwrap();
+}
+
+const DOUBLE_STAR: &str = "**";
+
+fn is_wildcard(segment: &str) -> bool {
+    segment.contains(['*', '?'])
+}
+
+// Matches a single path segment: `*` matches any sequence of characters, `?` any character.
+fn match_segment(pattern: &[u8], value: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => value.is_empty(),
+        Some((b'*', rest)) => (0..=value.len()).any(|i| match_segment(rest, &value[i..])),
+        Some((b'?', rest)) => !value.is_empty() && match_segment(rest, &value[1..]),
+        Some((c, rest)) => value.first() == Some(c) && match_segment(rest, &value[1..]),
+    }
+}
+
+fn split_segment(path: &str) -> (&str, Option<&str>) {
+    match path.split_once('/') {
+        Some((first, tail)) => (first, Some(tail)),
+        None => (path, None),
+    }
+}
+
+// Matches the segments of a path, `None` being the empty path once every segment is consumed
+fn match_segments<S: AsRef<str>>(pattern: &[S], path: Option<&str>) -> bool {
+    match pattern.split_first() {
+        None => path.is_none(),
+        Some((segment, rest)) if segment.as_ref() == DOUBLE_STAR => {
+            let mut path = path;
+            loop {
+                if match_segments(rest, path) {
+                    return true;
+                }
+                match path {
+                    Some(remaining) => path = split_segment(remaining).1,
+                    None => return false,
+                }
+            }
+        }
+        Some((segment, rest)) => path.is_some_and(|path| {
+            let (first, tail) = split_segment(path);
+            match_segment(segment.as_ref().as_bytes(), first.as_bytes())
+                && match_segments(rest, tail)
+        }),
+    }
+}
+
+// Matches a whole path against a glob pattern, e.g. `team/*` or `mirror/**`
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.split('/').collect::<Vec<_>>();
+    match_segments(&pattern, Some(path))
+}
+
+// A repository namespace from the configuration, which may contain glob patterns.
+#[derive(Clone, Debug)]
+pub struct RepositoryPattern {
+    pattern: String,
+    segments: Vec<String>,
+}
+
+impl RepositoryPattern {
+    pub fn parse(pattern: &str) -> Option<Self> {
+        if !pattern
+            .split('/')
+            .all(|segment| SEGMENT_RE.is_match(segment))
+        {
+            return None;
+        }
+
+        Some(RepositoryPattern {
+            pattern: pattern.to_string(),
+            segments: pattern.split('/').map(str::to_string).collect(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    // Matches the namespace itself or any of its parents, on path segment boundaries:
+    // `team` matches `team` and `team/app`, but not `team-a` nor `teamx/app`.
+    pub fn matches(&self, namespace: &str) -> bool {
+        namespace
+            .match_indices('/')
+            .map(|(index, _)| &namespace[..index])
+            .chain([namespace])
+            .any(|path| match_segments(&self.segments, Some(path)))
+    }
+
+    // Patterns with more literal segments are more specific, then longer patterns,
+    // then patterns whose literal segments come first.
+    fn specificity(&self) -> (usize, usize, Vec<bool>) {
+        let segments = self
+            .segments
+            .iter()
+            .filter(|&segment| segment != DOUBLE_STAR)
+            .collect::<Vec<_>>();
+        let literals = segments
+            .iter()
+            .map(|segment| !is_wildcard(segment))
+            .collect::<Vec<_>>();
+
+        (
+            literals.iter().filter(|&&literal| literal).count(),
+            segments.len(),
+            literals,
+        )
+    }
+
+    // Orders the most specific patterns first, with a stable order between equivalent ones
+    pub fn cmp_specificity(&self, other: &Self) -> Ordering {
+        other
+            .specificity()
+            .cmp(&self.specificity())
+            .then_with(|| self.pattern.cmp(&other.pattern))
+    }
+}
@@ -1,4 +1,5 @@
-use futures_util::{Stream, StreamExt};
+use crate::metrics::METRICS;
+use futures_util::{future, stream, Stream, StreamExt};
 use http_body_util::{Full, StreamBody};
 use hyper::body::{Body, Bytes, Frame};
 use std::io;
@@ -32,6 +33,20 @@ impl RegistryResponseBody {
         let stream = ReaderStream::new(reader).map(|result| result.map(Frame::data));
         RegistryResponseBody::Streaming(StreamBody::new(Box::pin(stream)))
     }
+
+    // Streams each reader preceded by its part header, then the trailer (multipart responses)
+    pub fn streaming_parts<R>(parts: Vec<(Bytes, R)>, trailer: Bytes) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let stream = stream::iter(parts)
+            .flat_map(|(header, reader)| {
+                stream::once(future::ready(Ok(header))).chain(ReaderStream::new(reader))
+            })
+            .chain(stream::once(future::ready(Ok(trailer))))
+            .map(|result| result.map(Frame::data));
+        RegistryResponseBody::Streaming(StreamBody::new(Box::pin(stream)))
+    }
 }
 
 impl Body for RegistryResponseBody {
@@ -42,12 +57,20 @@ impl Body for RegistryResponseBody {
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
-        match self.get_mut() {
+        let res = match self.get_mut() {
             RegistryResponseBody::Empty => Poll::Ready(None),
-            RegistryResponseBody::Fixed(body) => Pin::new(body)
-                .poll_frame(cx)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            RegistryResponseBody::Fixed(body) => {
+                Pin::new(body).poll_frame(cx).map_err(io::Error::other)
+            }
             RegistryResponseBody::Streaming(body) => Pin::new(body).poll_frame(cx),
+        };
+
+        if let Poll::Ready(Some(Ok(frame))) = &res {
+            if let Some(data) = frame.data_ref() {
+                METRICS.observe_sent_bytes(data.len() as u64);
+            }
         }
+
+        res
     }
 }
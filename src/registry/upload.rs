@@ -1,10 +1,13 @@
 use crate::error::RegistryError;
+use crate::metrics::METRICS;
 use crate::oci::Digest;
-use crate::registry::Registry;
-use futures_util::StreamExt;
+use crate::policy::ClientIdentity;
+use crate::registry::{validate_namespace, EventAction, EventTarget, LinkReference, Registry};
+use futures_util::{future, stream, StreamExt};
 use http_body_util::BodyDataStream;
 use hyper::body::Incoming;
 use hyper::Request;
+use tokio_util::io::StreamReader;
 use tracing::{debug, error, instrument, warn};
 use uuid::Uuid;
 
@@ -20,10 +23,12 @@ impl Registry {
         namespace: &str,
         digest: Option<Digest>,
     ) -> Result<NewUpload, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
         if let Some(digest) = digest {
-            if self.storage.get_blob_size(&digest).await.is_ok() {
+            if self.check_blob_access(namespace, &digest).await.is_ok()
+                && self.storage.get_blob_size(&digest).await.is_ok()
+            {
                 return Ok(NewUpload::ExistingBlob(digest));
             }
         }
@@ -35,24 +40,74 @@ impl Registry {
         Ok(NewUpload::Session(location, session_uuid))
     }
 
+    #[instrument(skip(identity))]
+    pub async fn mount_blob(
+        &self,
+        namespace: &str,
+        from: &str,
+        digest: &Digest,
+        identity: &ClientIdentity,
+    ) -> Result<bool, RegistryError> {
+        validate_namespace(namespace)?;
+        validate_namespace(from)?;
+
+        if !self.is_blob_linked(from, digest).await? {
+            debug!("Blob {} is not linked in namespace {}", digest, from);
+            return Ok(false);
+        }
+
+        let link = LinkReference::Layer(digest.clone());
+        self.storage.create_link(namespace, &link, digest).await?;
+
+        let size = self.storage.get_blob_size(digest).await?;
+        let target = EventTarget::blob(namespace, digest, size);
+        self.notify(EventAction::Push, target, identity);
+
+        Ok(true)
+    }
+
+    pub fn upload_chunk_min_length(&self) -> Option<u64> {
+        self.storage.upload_chunk_min_length()
+    }
+
     #[instrument(skip(body))]
     pub async fn patch_upload(
         &self,
         namespace: &str,
         session_id: Uuid,
-        start_offset: Option<u64>,
+        range: Option<(u64, u64)>,
+        content_length: Option<u64>,
         body: BodyDataStream<Request<Incoming>>,
     ) -> Result<u64, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
+
+        // The body length is enforced against Content-Length by hyper, so a mismatch with the
+        // range is rejected before anything is written. Without Content-Length (chunked or
+        // HTTP/2 bodies), the range is checked against the bytes actually received.
+        let range_length = range.map(|(start, end)| end - start + 1);
+        if let (Some(range_length), Some(content_length)) = (range_length, content_length) {
+            if range_length != content_length {
+                warn!(
+                    "Content-Range length ({}) does not match Content-Length ({})",
+                    range_length, content_length
+                );
+                return Err(RegistryError::SizeInvalid);
+            }
+        }
 
         let session_id = session_id.to_string();
-        if let Some(start_offset) = start_offset {
-            let summary = self
-                .storage
-                .read_upload_summary(namespace, &session_id)
-                .await?;
+        let start_size = self
+            .storage
+            .read_upload_summary(namespace, &session_id)
+            .await?
+            .size;
 
-            if start_offset != summary.size {
+        if let Some((start, _)) = range {
+            if start != start_size {
+                warn!(
+                    "Content-Range start ({}) does not match upload size ({})",
+                    start, start_size
+                );
                 return Err(RegistryError::RangeNotSatisfiable);
             }
         };
@@ -69,6 +124,17 @@ impl Registry {
                 e
             })?;
 
+        if let Some(range_length) = range_length {
+            let received_length = summary.size - start_size;
+            if received_length != range_length {
+                warn!(
+                    "Content-Range length ({}) does not match received length ({})",
+                    range_length, received_length
+                );
+                return Err(RegistryError::SizeInvalid);
+            }
+        }
+
         if summary.size < 1 {
             return Ok(0);
         }
@@ -76,15 +142,16 @@ impl Registry {
         Ok(summary.size - 1)
     }
 
-    #[instrument(skip(body))]
+    #[instrument(skip(body, identity))]
     pub async fn complete_upload(
         &self,
         namespace: &str,
         session_id: Uuid,
         digest: Digest,
         body: BodyDataStream<Request<Incoming>>,
+        identity: &ClientIdentity,
     ) -> Result<(), RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
         let session_id = session_id.to_string();
 
@@ -102,51 +169,118 @@ impl Registry {
         }
 
         self.storage
-            .complete_upload(namespace, &session_id, Some(digest))
+            .complete_upload(namespace, &session_id, Some(digest.clone()))
             .await?;
-        self.storage.delete_upload(namespace, &session_id).await
+
+        // Link the blob to the namespace, so it can be accessed before being referenced by a manifest
+        let link = LinkReference::Layer(digest.clone());
+        self.storage.create_link(namespace, &link, &digest).await?;
+
+        self.storage.delete_upload(namespace, &session_id).await?;
+
+        let target = EventTarget::blob(namespace, &digest, summary.size);
+        self.notify(EventAction::Push, target, identity);
+
+        Ok(())
     }
 
-    async fn upload_body_chunk(
+    #[instrument(skip(body, identity))]
+    pub async fn upload_blob(
         &self,
         namespace: &str,
-        session_id: &str,
-        mut body: BodyDataStream<Request<Incoming>>,
-        mut append: bool,
+        digest: Digest,
+        content_length: u64,
+        body: BodyDataStream<Request<Incoming>>,
+        identity: &ClientIdentity,
     ) -> Result<(), RegistryError> {
-        let mut chunk = Vec::new();
-        while let Some(frame) = body.next().await {
-            let frame = frame.map_err(|e| {
-                error!("Data stream error: {}", e);
-                std::io::Error::new(std::io::ErrorKind::Other, e)
-            })?;
-            chunk.extend_from_slice(&frame);
+        validate_namespace(namespace)?;
 
-            let streaming_size = self.streaming_chunk_size as usize;
+        let session_id = Uuid::new_v4().to_string();
+        self.storage.create_upload(namespace, &session_id).await?;
 
-            while chunk.len() >= streaming_size {
-                debug!("Chunk too large, creating a part: {}", chunk.len());
-                let (current_part, next_part) = chunk.split_at(streaming_size);
+        let res = async {
+            self.upload_body_chunk(namespace, &session_id, body, false)
+                .await?;
 
-                self.storage
-                    .write_upload(namespace, session_id, current_part, append)
-                    .await?;
+            let summary = self
+                .storage
+                .read_upload_summary(namespace, &session_id)
+                .await?;
 
-                if !append {
-                    append = true;
-                }
-                chunk = next_part.to_vec();
+            if summary.size != content_length {
+                warn!("Expected size '{}', got '{}'", content_length, summary.size);
+                return Err(RegistryError::SizeInvalid);
+            }
+
+            if summary.digest != digest {
+                warn!("Expected digest '{}', got '{}'", digest, summary.digest);
+                return Err(RegistryError::DigestInvalid);
             }
-        }
 
-        if !chunk.is_empty() {
-            debug!("Remaining chunk data, creating a part: {}", chunk.len());
             self.storage
-                .write_upload(namespace, session_id, &chunk, append)
+                .complete_upload(namespace, &session_id, Some(digest.clone()))
                 .await?;
+
+            let link = LinkReference::Layer(digest.clone());
+            self.storage.create_link(namespace, &link, &digest).await
         }
+        .await;
 
-        Ok(())
+        if let Err(e) = self.storage.delete_upload(namespace, &session_id).await {
+            warn!("Failed to delete upload session '{}': {:?}", session_id, e);
+        }
+
+        if res.is_ok() {
+            let target = EventTarget::blob(namespace, &digest, content_length);
+            self.notify(EventAction::Push, target, identity);
+        }
+
+        res
+    }
+
+    async fn upload_body_chunk(
+        &self,
+        namespace: &str,
+        session_id: &str,
+        body: BodyDataStream<Request<Incoming>>,
+        append: bool,
+    ) -> Result<(), RegistryError> {
+        let _active_upload = METRICS.track_upload();
+
+        let mut body = body
+            .map(|frame| {
+                frame.map_err(|e| {
+                    error!("Data stream error: {}", e);
+                    std::io::Error::other(e)
+                })
+            })
+            .inspect(|frame| {
+                if let Ok(frame) = frame {
+                    METRICS.observe_received_bytes(frame.len() as u64);
+                }
+            });
+
+        // Wait for some data before touching the upload, so an empty body leaves it unchanged
+        let first_frame = loop {
+            match body.next().await {
+                Some(frame) => {
+                    let frame = frame?;
+                    if !frame.is_empty() {
+                        break frame;
+                    }
+                }
+                None => return Ok(()),
+            }
+        };
+
+        // The body is consumed as the storage backend writes it, so the amount of buffered
+        // data is bounded by the backend, not by the size of the body.
+        let body = stream::once(future::ready(Ok(first_frame))).chain(body);
+        let reader = StreamReader::new(body);
+
+        self.storage
+            .write_upload(namespace, session_id, Box::new(reader), append)
+            .await
     }
 
     #[instrument]
@@ -155,7 +289,7 @@ impl Registry {
         namespace: &str,
         session_id: Uuid,
     ) -> Result<(), RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
         let uuid = session_id.to_string();
         self.storage.delete_upload(namespace, &uuid).await
@@ -167,7 +301,7 @@ impl Registry {
         namespace: &str,
         session_id: Uuid,
     ) -> Result<u64, RegistryError> {
-        self.validate_namespace(namespace)?;
+        validate_namespace(namespace)?;
 
         let uuid = session_id.to_string();
         let summary = self.storage.read_upload_summary(namespace, &uuid).await?;
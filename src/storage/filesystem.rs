@@ -8,11 +8,11 @@ use std::io::{ErrorKind, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs::{self, File};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tracing::{debug, error, instrument, warn};
 
 use crate::error::RegistryError;
-use crate::lock_manager::LockManager;
+use crate::lock_manager::{LockManager, WriteGuard};
 use crate::oci::{Descriptor, Digest, Manifest};
 use crate::registry::LinkReference;
 use crate::storage::tree_manager::TreeManager;
@@ -21,6 +21,8 @@ use crate::storage::{
     StorageEngine, StorageEngineReader, UploadSummary,
 };
 
+const UPLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
 #[derive(Clone)]
 pub struct FileSystemStorageEngine {
     lock_manager: LockManager,
@@ -226,6 +228,36 @@ impl FileSystemStorageEngine {
 
 #[async_trait]
 impl StorageEngine for FileSystemStorageEngine {
+    #[instrument(skip(self))]
+    async fn check_health(&self) -> Result<(), RegistryError> {
+        let metadata = fs::metadata(&self.tree.root_dir).await.map_err(|e| {
+            error!("Unable to access storage root directory: {}", e);
+            RegistryError::InternalServerError(Some(
+                "Unable to access storage root directory".to_string(),
+            ))
+        })?;
+
+        if !metadata.is_dir() {
+            return Err(RegistryError::InternalServerError(Some(
+                "Storage root is not a directory".to_string(),
+            )));
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn check_lock_health(&self) -> Result<(), RegistryError> {
+        self.lock_manager.check_health().await
+    }
+
+    #[instrument(skip(self))]
+    async fn lock_tag(&self, namespace: &str, tag: &str) -> Result<WriteGuard, RegistryError> {
+        self.lock_manager
+            .write_lock(format!("tag:{}:{}", namespace, tag))
+            .await
+    }
+
     #[instrument(skip(self))]
     async fn list_namespaces(
         &self,
@@ -299,6 +331,7 @@ impl StorageEngine for FileSystemStorageEngine {
                 size: manifest_len as u64,
                 annotations: manifest.annotations,
                 artifact_type: manifest.artifact_type,
+                platform: None,
             });
         }
 
@@ -374,6 +407,10 @@ impl StorageEngine for FileSystemStorageEngine {
         Ok(self.paginate(revisions, n, continuation_token))
     }
 
+    fn upload_chunk_min_length(&self) -> Option<u64> {
+        None
+    }
+
     #[instrument(skip(self))]
     async fn create_upload(&self, name: &str, uuid: &str) -> Result<String, RegistryError> {
         let _guard = self
@@ -409,7 +446,7 @@ impl StorageEngine for FileSystemStorageEngine {
         &self,
         name: &str,
         uuid: &str,
-        source: &[u8],
+        mut source: Box<dyn StorageEngineReader>,
         append: bool,
     ) -> Result<(), RegistryError> {
         let start_offset = if append {
@@ -438,8 +475,6 @@ impl StorageEngine for FileSystemStorageEngine {
                 }
             })?;
 
-        file.seek(SeekFrom::Start(start_offset)).await?;
-
         let path = self
             .tree
             .upload_hash_context_path(name, uuid, "sha256", start_offset);
@@ -448,11 +483,28 @@ impl StorageEngine for FileSystemStorageEngine {
 
         file.seek(SeekFrom::Start(start_offset)).await?;
 
+        // The data is written and hashed as it is received, so memory usage is bounded by
+        // the buffer size, whatever the size of the upload.
+        let mut buffer = vec![0; UPLOAD_BUFFER_SIZE];
         let mut total_bytes_written = 0u64;
 
-        file.write_all(source).await?;
-        hasher.update(source);
-        total_bytes_written += source.len() as u64;
+        let res = loop {
+            let n = match source.read(&mut buffer).await {
+                Ok(0) => break Ok(()),
+                Ok(n) => n,
+                Err(e) => break Err(e),
+            };
+
+            if let Err(e) = file.write_all(&buffer[..n]).await {
+                break Err(e);
+            }
+            hasher.update(&buffer[..n]);
+            total_bytes_written += n as u64;
+        };
+
+        // Always persist the hash state of the data written so far, so an interrupted upload
+        // can be resumed from its current size.
+        file.flush().await?;
 
         let offset = start_offset + total_bytes_written;
         let path = self
@@ -461,7 +513,10 @@ impl StorageEngine for FileSystemStorageEngine {
         let state = serialize_hash_state(&hasher).await?;
         fs::write(&path, &state).await?;
 
-        Ok(())
+        res.map_err(|e| {
+            error!("Error writing upload data: {}", e);
+            e.into()
+        })
     }
 
     #[instrument(skip(self))]
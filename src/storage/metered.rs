@@ -0,0 +1,231 @@
+use async_trait::async_trait;
+use std::future::Future;
+use std::time::Instant;
+
+use crate::error::RegistryError;
+use crate::lock_manager::WriteGuard;
+use crate::metrics::METRICS;
+use crate::oci::{Descriptor, Digest};
+use crate::registry::LinkReference;
+use crate::storage::{BlobReferenceIndex, StorageEngine, StorageEngineReader, UploadSummary};
+
+// Records the latency of every operation of the wrapped storage engine
+pub struct MeteredStorageEngine {
+    backend: &'static str,
+    inner: Box<dyn StorageEngine>,
+}
+
+impl MeteredStorageEngine {
+    pub fn new(backend: &'static str, inner: Box<dyn StorageEngine>) -> Self {
+        Self { backend, inner }
+    }
+
+    async fn timed<T>(&self, operation: &str, future: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let res = future.await;
+        METRICS.observe_storage_operation(self.backend, operation, start.elapsed());
+        res
+    }
+}
+
+#[async_trait]
+impl StorageEngine for MeteredStorageEngine {
+    async fn check_health(&self) -> Result<(), RegistryError> {
+        self.timed("check_health", self.inner.check_health()).await
+    }
+
+    async fn check_lock_health(&self) -> Result<(), RegistryError> {
+        self.inner.check_lock_health().await
+    }
+
+    async fn lock_tag(&self, namespace: &str, tag: &str) -> Result<WriteGuard, RegistryError> {
+        self.inner.lock_tag(namespace, tag).await
+    }
+
+    async fn list_namespaces(
+        &self,
+        n: u32,
+        last: Option<String>,
+    ) -> Result<(Vec<String>, Option<String>), RegistryError> {
+        self.timed("list_namespaces", self.inner.list_namespaces(n, last))
+            .await
+    }
+
+    async fn list_tags(
+        &self,
+        namespace: &str,
+        n: u32,
+        last: Option<String>,
+    ) -> Result<(Vec<String>, Option<String>), RegistryError> {
+        self.timed("list_tags", self.inner.list_tags(namespace, n, last))
+            .await
+    }
+
+    async fn list_referrers(
+        &self,
+        namespace: &str,
+        digest: &Digest,
+        artifact_type: Option<String>,
+    ) -> Result<Vec<Descriptor>, RegistryError> {
+        self.timed(
+            "list_referrers",
+            self.inner.list_referrers(namespace, digest, artifact_type),
+        )
+        .await
+    }
+
+    async fn list_uploads(
+        &self,
+        namespace: &str,
+        n: u32,
+        continuation_token: Option<String>,
+    ) -> Result<(Vec<String>, Option<String>), RegistryError> {
+        self.timed(
+            "list_uploads",
+            self.inner.list_uploads(namespace, n, continuation_token),
+        )
+        .await
+    }
+
+    async fn list_blobs(
+        &self,
+        n: u32,
+        continuation_token: Option<String>,
+    ) -> Result<(Vec<Digest>, Option<String>), RegistryError> {
+        self.timed("list_blobs", self.inner.list_blobs(n, continuation_token))
+            .await
+    }
+
+    async fn list_revisions(
+        &self,
+        namespace: &str,
+        n: u32,
+        continuation_token: Option<String>,
+    ) -> Result<(Vec<Digest>, Option<String>), RegistryError> {
+        self.timed(
+            "list_revisions",
+            self.inner.list_revisions(namespace, n, continuation_token),
+        )
+        .await
+    }
+
+    fn upload_chunk_min_length(&self) -> Option<u64> {
+        self.inner.upload_chunk_min_length()
+    }
+
+    async fn create_upload(&self, namespace: &str, uuid: &str) -> Result<String, RegistryError> {
+        self.timed("create_upload", self.inner.create_upload(namespace, uuid))
+            .await
+    }
+
+    async fn write_upload(
+        &self,
+        namespace: &str,
+        uuid: &str,
+        source: Box<dyn StorageEngineReader>,
+        append: bool,
+    ) -> Result<(), RegistryError> {
+        self.timed(
+            "write_upload",
+            self.inner.write_upload(namespace, uuid, source, append),
+        )
+        .await
+    }
+
+    async fn read_upload_summary(
+        &self,
+        namespace: &str,
+        uuid: &str,
+    ) -> Result<UploadSummary, RegistryError> {
+        self.timed(
+            "read_upload_summary",
+            self.inner.read_upload_summary(namespace, uuid),
+        )
+        .await
+    }
+
+    async fn complete_upload(
+        &self,
+        namespace: &str,
+        uuid: &str,
+        digest: Option<Digest>,
+    ) -> Result<Digest, RegistryError> {
+        self.timed(
+            "complete_upload",
+            self.inner.complete_upload(namespace, uuid, digest),
+        )
+        .await
+    }
+
+    async fn delete_upload(&self, namespace: &str, uuid: &str) -> Result<(), RegistryError> {
+        self.timed("delete_upload", self.inner.delete_upload(namespace, uuid))
+            .await
+    }
+
+    async fn create_blob(&self, content: &[u8]) -> Result<Digest, RegistryError> {
+        self.timed("create_blob", self.inner.create_blob(content))
+            .await
+    }
+
+    async fn read_blob(&self, digest: &Digest) -> Result<Vec<u8>, RegistryError> {
+        self.timed("read_blob", self.inner.read_blob(digest)).await
+    }
+
+    async fn read_blob_index(&self, digest: &Digest) -> Result<BlobReferenceIndex, RegistryError> {
+        self.timed("read_blob_index", self.inner.read_blob_index(digest))
+            .await
+    }
+
+    async fn get_blob_size(&self, digest: &Digest) -> Result<u64, RegistryError> {
+        self.timed("get_blob_size", self.inner.get_blob_size(digest))
+            .await
+    }
+
+    async fn build_blob_reader(
+        &self,
+        digest: &Digest,
+        start_offset: Option<u64>,
+    ) -> Result<Box<dyn StorageEngineReader>, RegistryError> {
+        self.timed(
+            "build_blob_reader",
+            self.inner.build_blob_reader(digest, start_offset),
+        )
+        .await
+    }
+
+    async fn delete_blob(&self, digest: &Digest) -> Result<(), RegistryError> {
+        self.timed("delete_blob", self.inner.delete_blob(digest))
+            .await
+    }
+
+    async fn read_link(
+        &self,
+        namespace: &str,
+        reference: &LinkReference,
+    ) -> Result<Digest, RegistryError> {
+        self.timed("read_link", self.inner.read_link(namespace, reference))
+            .await
+    }
+
+    async fn create_link(
+        &self,
+        namespace: &str,
+        reference: &LinkReference,
+        digest: &Digest,
+    ) -> Result<(), RegistryError> {
+        self.timed(
+            "create_link",
+            self.inner.create_link(namespace, reference, digest),
+        )
+        .await
+    }
+
+    async fn delete_link(
+        &self,
+        namespace: &str,
+        reference: &LinkReference,
+    ) -> Result<(), RegistryError> {
+        self.timed("delete_link", self.inner.delete_link(namespace, reference))
+            .await
+    }
+}
@@ -1,4 +1,5 @@
 mod filesystem;
+mod metered;
 mod reference;
 mod s3;
 mod tree_manager;
@@ -11,10 +12,12 @@ use std::fmt::{Debug, Formatter};
 use tokio::io::AsyncRead;
 
 pub use filesystem::FileSystemStorageEngine;
+pub use metered::MeteredStorageEngine;
 pub use reference::BlobReferenceIndex;
 pub use s3::S3StorageEngine;
 
 use crate::error::RegistryError;
+use crate::lock_manager::WriteGuard;
 use crate::oci::{Descriptor, Digest};
 use crate::registry::LinkReference;
 
@@ -29,6 +32,15 @@ impl<T> StorageEngineReader for T where T: AsyncRead + Unpin + Send {}
 
 #[async_trait]
 pub trait StorageEngine: Send + Sync {
+    // Cheap check that the backend is reachable, used by the readiness probe
+    async fn check_health(&self) -> Result<(), RegistryError>;
+
+    // Same as `check_health`, for the lock manager guarding the storage operations
+    async fn check_lock_health(&self) -> Result<(), RegistryError>;
+
+    // Exclusive lock on a tag, for updates that depend on its current value
+    async fn lock_tag(&self, namespace: &str, tag: &str) -> Result<WriteGuard, RegistryError>;
+
     async fn list_namespaces(
         &self,
         n: u32,
@@ -69,13 +81,18 @@ pub trait StorageEngine: Send + Sync {
         continuation_token: Option<String>,
     ) -> Result<(Vec<Digest>, Option<String>), RegistryError>;
 
+    // Minimum size of upload chunks (except the last one) supported by the backend, if any
+    fn upload_chunk_min_length(&self) -> Option<u64>;
+
     async fn create_upload(&self, namespace: &str, uuid: &str) -> Result<String, RegistryError>;
 
+    // Consumes the source until its end, the data is appended to the upload if `append` is true,
+    // otherwise it is written from the start of the upload
     async fn write_upload(
         &self,
         namespace: &str,
         uuid: &str,
-        source: &[u8],
+        source: Box<dyn StorageEngineReader>,
         append: bool,
     ) -> Result<(), RegistryError>;
 
@@ -130,7 +147,7 @@ pub trait StorageEngine: Send + Sync {
     ) -> Result<(), RegistryError>;
 }
 
-impl Debug for (dyn StorageEngine + 'static) {
+impl Debug for dyn StorageEngine + 'static {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("StorageEngine").finish()
     }
@@ -17,11 +17,12 @@ use aws_sdk_s3::{
 use chrono::{DateTime, Utc};
 use futures_util::future::try_join_all;
 use sha2::{Digest as ShaDigestTrait, Sha256};
-use tracing::{debug, error, instrument};
+use tokio::io::AsyncReadExt;
+use tracing::{debug, error, instrument, warn};
 
 use crate::configuration::StorageS3Config;
 use crate::error::RegistryError;
-use crate::lock_manager::LockManager;
+use crate::lock_manager::{LockManager, WriteGuard};
 use crate::oci::{Descriptor, Digest, Manifest};
 use crate::registry::LinkReference;
 use crate::storage::tree_manager::TreeManager;
@@ -40,6 +41,7 @@ pub struct S3StorageEngine {
     multipart_copy_chunk_size: u64,
     multipart_copy_jobs: usize,
     multipart_min_part_size: u64,
+    multipart_part_size: u64,
 }
 
 impl Debug for S3StorageEngine {
@@ -85,6 +87,7 @@ impl S3StorageEngine {
             multipart_copy_chunk_size: config.multipart_copy_chunk_size.as_bytes(),
             multipart_copy_jobs: config.multipart_copy_jobs,
             multipart_min_part_size: config.multipart_min_part_size.as_bytes(),
+            multipart_part_size: config.multipart_part_size.as_bytes(),
         })
     }
 
@@ -239,9 +242,17 @@ impl S3StorageEngine {
         let key = self
             .tree
             .upload_staged_container_path(namespace, upload_id, offset);
-        self.put_object(&key, chunk.to_vec()).await
+        self.put_object(&key, chunk).await
     }
 
+    #[instrument(skip(self, hasher))]
+    async fn store_hash_state(&self, key: &str, hasher: &Sha256) -> Result<(), RegistryError> {
+        let state = serialize_hash_state(hasher).await?;
+        self.put_object(key, state).await
+    }
+
+    // The staged chunk is kept until its data is part of an uploaded part, so that a failed
+    // write leaves the upload as it was. Stale chunks are removed with the upload container.
     #[instrument(skip(self))]
     async fn load_staged_chunk(
         &self,
@@ -253,10 +264,7 @@ impl S3StorageEngine {
             .tree
             .upload_staged_container_path(namespace, upload_id, offset);
         match self.get_object_body_as_vec(&key, None).await {
-            Ok(data) => {
-                self.delete_object(&key).await?;
-                Ok(data)
-            }
+            Ok(data) => Ok(data),
             Err(RegistryError::NotFound) => Ok(Vec::new()),
             Err(e) => Err(e),
         }
@@ -525,7 +533,7 @@ impl S3StorageEngine {
         key: &str,
         upload_id: &str,
         part_number: i32,
-        body: Vec<u8>,
+        body: &[u8],
     ) -> Result<String, RegistryError> {
         let body = ByteStream::from(body.to_vec());
 
@@ -562,6 +570,29 @@ impl S3StorageEngine {
 
 #[async_trait]
 impl StorageEngine for S3StorageEngine {
+    #[instrument(skip(self))]
+    async fn check_health(&self) -> Result<(), RegistryError> {
+        self.s3_client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn check_lock_health(&self) -> Result<(), RegistryError> {
+        self.lock_manager.check_health().await
+    }
+
+    #[instrument(skip(self))]
+    async fn lock_tag(&self, namespace: &str, tag: &str) -> Result<WriteGuard, RegistryError> {
+        self.lock_manager
+            .write_lock(format!("tag:{}:{}", namespace, tag))
+            .await
+    }
+
     #[instrument(skip(self))]
     async fn list_namespaces(
         &self,
@@ -730,6 +761,7 @@ impl StorageEngine for S3StorageEngine {
                     size: manifest_len as u64,
                     annotations: manifest.annotations,
                     artifact_type: manifest.artifact_type,
+                    platform: None,
                 });
             }
 
@@ -887,6 +919,10 @@ impl StorageEngine for S3StorageEngine {
         Ok((revisions, next_last))
     }
 
+    fn upload_chunk_min_length(&self) -> Option<u64> {
+        Some(self.multipart_min_part_size)
+    }
+
     #[instrument(skip(self))]
     async fn create_upload(&self, name: &str, uuid: &str) -> Result<String, RegistryError> {
         let date_path = self.tree.upload_start_date_path(name, uuid);
@@ -905,13 +941,13 @@ impl StorageEngine for S3StorageEngine {
         &self,
         name: &str,
         uuid: &str,
-        source: &[u8],
+        mut source: Box<dyn StorageEngineReader>,
         append: bool,
     ) -> Result<(), RegistryError> {
         let upload_path = self.tree.upload_path(name, uuid);
 
-        let uploaded_size;
-        let uploaded_parts;
+        let mut uploaded_size;
+        let mut uploaded_parts;
         let upload_id;
 
         if append {
@@ -934,56 +970,93 @@ impl StorageEngine for S3StorageEngine {
             uploaded_parts = 0;
         }
 
-        let staged_size = self
-            .head_object(
-                &self
-                    .tree
-                    .upload_staged_container_path(name, uuid, uploaded_size),
-            )
-            .await
-            .ok()
-            .map(|object| object.content_length.unwrap_or_default() as u64)
-            .unwrap_or_default();
+        // NOTE: parts must be at least 5M (as per the S3 protocol), except the last one.
+        // Data is buffered until a full part is received, and the remainder is stored as a
+        // staging blob. First, we load the staged chunk if any to append the new data to it.
+        let mut chunk = self.load_staged_chunk(name, uuid, uploaded_size).await?;
+        let mut staged_offset = (!chunk.is_empty()).then_some(uploaded_size);
 
-        let hasher_state_path =
-            self.tree
-                .upload_hash_context_path(name, uuid, "sha256", uploaded_size + staged_size);
+        let hasher_state_path = self.tree.upload_hash_context_path(
+            name,
+            uuid,
+            "sha256",
+            uploaded_size + chunk.len() as u64,
+        );
         let state = self
             .get_object_body_as_vec(&hasher_state_path, None)
             .await?;
         let mut hasher = deserialize_hash_state(state).await?;
 
-        hasher.update(source);
+        let part_size = self.multipart_part_size;
 
-        // NOTE: if the part is not big enough (at least 5M, as per the S3 protocol),
-        // we store it in as a staging blob.
-        // First, we load the staged chunk if any and append the new data
-        let mut chunk = self.load_staged_chunk(name, uuid, uploaded_size).await?;
-        chunk.extend(source);
-        let chunk_len = chunk.len() as u64;
+        let res = loop {
+            // Only one part is buffered at a time, reading stops when it is full
+            let start = chunk.len();
+            let limit = part_size.saturating_sub(start as u64);
+            chunk.reserve_exact(limit as usize);
+            let read = (&mut source).take(limit).read_to_end(&mut chunk).await;
+            hasher.update(&chunk[start..]);
+
+            if let Err(e) = read {
+                error!("Error reading upload data: {}", e);
+                break Err(RegistryError::from(e));
+            }
+
+            if (chunk.len() as u64) < part_size {
+                break Ok(());
+            }
+
+            // The part is only dropped once uploaded, the bytes are staged back otherwise
+            if let Err(e) = self
+                .upload_part(&upload_path, &upload_id, uploaded_parts + 1, &chunk)
+                .await
+            {
+                break Err(e);
+            }
+            uploaded_parts += 1;
+            uploaded_size += chunk.len() as u64;
+            chunk.clear();
+
+            if let Some(offset) = staged_offset.take() {
+                let key = self.tree.upload_staged_container_path(name, uuid, offset);
+                if let Err(e) = self.delete_object(&key).await {
+                    warn!("Failed to delete uploaded staged chunk '{}': {}", key, e);
+                }
+            }
+
+            let hash_state_path =
+                self.tree
+                    .upload_hash_context_path(name, uuid, "sha256", uploaded_size);
+            if let Err(e) = self.store_hash_state(&hash_state_path, &hasher).await {
+                break Err(e);
+            }
+        };
 
+        // If there is no subsequent calls to this method, the staged chunk will be loaded back
+        // and stored as last part in the complete_upload() method.
         // The hash computation must take into account:
         // - completed parts
-        // - current staged chunk if any + source: chunk.len()
-        let state = serialize_hash_state(&hasher).await?;
+        // - current staged chunk if any
+        // This is done on errors too, so that the upload can be resumed from its current size.
+        // The hash state is stored first: until the staged chunk is written, the upload size
+        // still points to the previous chunk and its hash state.
+        let chunk_len = chunk.len() as u64;
         let hash_state_path =
             self.tree
                 .upload_hash_context_path(name, uuid, "sha256", uploaded_size + chunk_len);
-        self.put_object(&hash_state_path, state).await?;
+        let mut stored = self.store_hash_state(&hash_state_path, &hasher).await;
 
-        // If the chunk is still too small, store it again and return.
-        // If there is no subsequent calls to this method, the chunk will be loaded back and stored
-        // as last part in the complete_upload() method.
-        if (chunk.len() as u64) < self.multipart_min_part_size {
-            self.store_staged_chunk(name, uuid, chunk, uploaded_size)
-                .await?;
-            return Ok(());
+        if stored.is_ok() && chunk_len > 0 {
+            stored = self
+                .store_staged_chunk(name, uuid, chunk, uploaded_size)
+                .await;
         }
 
-        self.upload_part(&upload_path, &upload_id, uploaded_parts + 1, chunk)
-            .await?;
+        if let (Err(_), Err(e)) = (&res, &stored) {
+            error!("Error storing upload state: {}", e);
+        }
 
-        Ok(())
+        res.and(stored)
     }
 
     #[instrument(skip(self))]
@@ -1059,7 +1132,7 @@ impl StorageEngine for S3StorageEngine {
             size += chunk.len() as u64;
 
             let e_tag = self
-                .upload_part(&key, &upload_id, parts.len() as i32 + 1, chunk)
+                .upload_part(&key, &upload_id, parts.len() as i32 + 1, &chunk)
                 .await?;
 
             parts.push(e_tag);
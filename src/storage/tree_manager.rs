@@ -212,6 +212,36 @@ impl TreeManager {
         format!("{}/link", self.manifest_tag_link_parent_dir(namespace, tag))
     }
 
+    pub fn manifest_index_dir(&self, name: &str, index: &Digest) -> String {
+        format!(
+            "{}/index/{}/{}",
+            self.manifests_root_dir(name),
+            index.algorithm(),
+            index.hash()
+        )
+    }
+
+    pub fn manifest_index_link_container_dir(
+        &self,
+        name: &str,
+        index: &Digest,
+        manifest: &Digest,
+    ) -> String {
+        format!("{}/{}", self.manifest_index_dir(name, index), manifest)
+    }
+
+    pub fn manifest_index_link_path(
+        &self,
+        name: &str,
+        index: &Digest,
+        manifest: &Digest,
+    ) -> String {
+        format!(
+            "{}/link",
+            self.manifest_index_link_container_dir(name, index, manifest)
+        )
+    }
+
     pub fn get_link_path(&self, reference: &LinkReference, name: &str) -> String {
         match reference {
             LinkReference::Tag(tag) => self.manifest_tag_link_path(name, tag),
@@ -221,6 +251,9 @@ impl TreeManager {
             LinkReference::Referrer(subject, referrer) => {
                 self.manifest_referrer_link_path(name, subject, referrer)
             }
+            LinkReference::Manifest(index, manifest) => {
+                self.manifest_index_link_path(name, index, manifest)
+            }
         }
     }
 
@@ -242,6 +275,9 @@ impl TreeManager {
             LinkReference::Referrer(subject, referrer) => {
                 self.manifest_referrer_link_container_dir(name, subject, referrer)
             }
+            LinkReference::Manifest(index, manifest) => {
+                self.manifest_index_link_container_dir(name, index, manifest)
+            }
         }
     }
 }